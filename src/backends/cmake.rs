@@ -0,0 +1,265 @@
+use std::env;
+
+use super::Generator;
+use crate::config::LibraryKind;
+use crate::utils::utilities::{build_path, Library, Linking, Makefile};
+
+/// Where CMake looks for the project
+pub static CMAKE_LISTS: &str = "CMakeLists.txt";
+
+/// CMake, with one target per executable and library
+#[derive(Debug)]
+pub struct CMake;
+
+/// The preprocessor flags of the project, split the way CMake wants them
+#[derive(Debug, Default)]
+struct PreprocessorSettings {
+    /// "NAME" or "NAME=VALUE", from -D
+    definitions: Vec<String>,
+    /// The -U flags, which CMake has no command for
+    options: Vec<String>,
+    /// From -iquote and -I
+    includes: Vec<String>,
+    /// From -isystem
+    system_includes: Vec<String>,
+}
+
+impl PreprocessorSettings {
+    fn from(cpp_flags: &[String]) -> Self {
+        let mut settings = PreprocessorSettings::default();
+        cpp_flags.iter().for_each(|flag| {
+            if let Some(dir) = flag.strip_prefix("-iquote ") {
+                settings.includes.push(dir.to_string());
+            } else if let Some(dir) = flag.strip_prefix("-isystem ") {
+                settings.system_includes.push(dir.to_string());
+            } else if let Some(dir) = flag.strip_prefix("-I") {
+                settings.includes.push(dir.to_string());
+            } else if let Some(define) = flag.strip_prefix("-D") {
+                settings.definitions.push(define.to_string());
+            } else {
+                settings.options.push(flag.clone());
+            }
+        });
+        settings
+    }
+}
+
+/// Quotes a CMake argument when it has anything CMake would split or expand
+fn quote(argument: &str) -> String {
+    let plain = !argument.is_empty()
+        && argument
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=./,:".contains(c));
+    if plain {
+        argument.to_string()
+    } else {
+        format!("\"{}\"", argument.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$"))
+    }
+}
+
+/// The path under the build directory of CMake, quoted when it has to be
+fn in_binary_dir(path: &str) -> String {
+    let quoted = quote(path);
+    match quoted.strip_prefix('"') {
+        Some(rest) => format!("\"${{CMAKE_BINARY_DIR}}/{}", rest),
+        None => format!("${{CMAKE_BINARY_DIR}}/{}", quoted),
+    }
+}
+
+fn arguments(arguments: &[String]) -> String {
+    arguments.iter().map(|argument| quote(argument)).collect::<Vec<String>>().join(" ")
+}
+
+/// The target of an executable or a library, named after its path: "src/server.c" -> "src_server"
+fn target_name(source: &str) -> String {
+    build_path(source).replace('/', "_")
+}
+
+impl CMake {
+    /// The flags every target gets: the compile options, the macros and the include directories
+    fn compile_settings(out: &mut String, target: &str, cflags: &[String], settings: &PreprocessorSettings) {
+        let mut options: Vec<String> = cflags.to_vec();
+        options.extend(settings.options.iter().cloned());
+        if !options.is_empty() {
+            out.push_str(format!("target_compile_options({} PRIVATE {})\n", target, arguments(&options)).as_str());
+        }
+        if !settings.definitions.is_empty() {
+            out.push_str(
+                format!("target_compile_definitions({} PRIVATE {})\n", target, arguments(&settings.definitions)).as_str(),
+            );
+        }
+        if !settings.includes.is_empty() {
+            out.push_str(
+                format!("target_include_directories({} PRIVATE {})\n", target, arguments(&settings.includes)).as_str(),
+            );
+        }
+        if !settings.system_includes.is_empty() {
+            out.push_str(
+                format!(
+                    "target_include_directories({} SYSTEM PRIVATE {})\n",
+                    target,
+                    arguments(&settings.system_includes)
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    fn library(out: &mut String, library: &Library, project: &Makefile, settings: &PreprocessorSettings) {
+        let kind = match library.kind {
+            LibraryKind::Static => "STATIC",
+            LibraryKind::Shared => "SHARED",
+        };
+        out.push_str(format!("add_library({} {} {})\n", library.name, kind, arguments(&library.sources)).as_str());
+        if let Some(version) = &library.version {
+            let major = version.split('.').next().unwrap_or(version);
+            out.push_str(
+                format!(
+                    "set_target_properties({} PROPERTIES VERSION {} SOVERSION {})\n",
+                    library.name,
+                    quote(version),
+                    quote(major)
+                )
+                .as_str(),
+            );
+        }
+        CMake::compile_settings(out, &library.name, project.c_flags(), settings);
+        if library.kind == LibraryKind::Shared {
+            CMake::link_settings(out, &library.name, project.ld_flags(), project.ld_libs());
+        }
+        out.push('\n');
+    }
+
+    fn link_settings(out: &mut String, target: &str, ld_flags: &[String], ld_libs: &[String]) {
+        if !ld_flags.is_empty() {
+            out.push_str(format!("target_link_options({} PRIVATE {})\n", target, arguments(ld_flags)).as_str());
+        }
+        if !ld_libs.is_empty() {
+            out.push_str(format!("target_link_libraries({} PRIVATE {})\n", target, arguments(ld_libs)).as_str());
+        }
+    }
+}
+
+impl Generator for CMake {
+    fn default_output(&self) -> &'static str {
+        CMAKE_LISTS
+    }
+
+    fn generate(&self, project: &Makefile, _output: &str) -> String {
+        let settings = PreprocessorSettings::from(project.cpp_flags());
+        let name = env::current_dir()
+            .ok()
+            .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or_else(|| "project".to_string());
+
+        let mut out = String::from("cmake_minimum_required(VERSION 3.13)\n");
+        // The compiler has to be chosen before project() looks for one
+        out.push_str(format!("set(CMAKE_C_COMPILER {})\n", quote(project.c_compiler())).as_str());
+        out.push_str(format!("project({} C)\n\n", quote(&name.replace(' ', "_"))).as_str());
+        out.push_str("set(CMAKE_RUNTIME_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/bin)\n");
+        out.push_str("set(CMAKE_LIBRARY_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/lib)\n");
+        out.push_str("set(CMAKE_ARCHIVE_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/lib)\n\n");
+
+        project
+            .libraries()
+            .iter()
+            .for_each(|library| CMake::library(&mut out, library, project, &settings));
+
+        project.linkings().into_iter().for_each(|linking| {
+            out.push_str(&linking.conditional_comment());
+            let Linking { source, sources, conditional, libraries: used, .. } = linking;
+            let target = target_name(&source);
+            let mut files = vec![source.clone()];
+            files.extend(sources);
+            files.extend(conditional);
+            out.push_str(format!("add_executable({} {})\n", target, arguments(&files)).as_str());
+            // Where make puts it too, so src/main.c and tools/main.c do not both make bin/main
+            let built = format!("bin/{}", build_path(&source));
+            let (directory, output_name) = built.rsplit_once('/').unwrap_or_default();
+            let mut properties = format!("RUNTIME_OUTPUT_DIRECTORY {}", in_binary_dir(directory));
+            if output_name != target {
+                properties = format!("OUTPUT_NAME {} {}", quote(output_name), properties);
+            }
+            out.push_str(format!("set_target_properties({} PROPERTIES {})\n", target, properties).as_str());
+
+            // The flags of the executable replace the ones of the project
+            let config = project.executable_config(&source);
+            let cflags = config.and_then(|config| config.cflags.as_deref()).unwrap_or(project.c_flags());
+            let ld_flags = config.and_then(|config| config.ldflags.as_deref()).unwrap_or(project.ld_flags());
+            let ld_libs = config.and_then(|config| config.ldlibs.as_deref()).unwrap_or(project.ld_libs());
+            CMake::compile_settings(&mut out, &target, cflags, &settings);
+            let mut libraries: Vec<String> = used.iter().map(|library| library.name.clone()).collect();
+            libraries.extend(ld_libs.iter().cloned());
+            CMake::link_settings(&mut out, &target, ld_flags, &libraries);
+            out.push('\n');
+        });
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::tests::project;
+
+    #[test]
+    fn quotes_what_cmake_would_split_or_expand() {
+        assert_eq!(quote("-O2"), "-O2");
+        assert_eq!(quote("LEVEL=2"), "LEVEL=2");
+        assert_eq!(quote("NAME=\"a b\""), "\"NAME=\\\"a b\\\"\"");
+        assert_eq!(quote("$ORIGIN"), "\"\\$ORIGIN\"");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(in_binary_dir("bin/src"), "${CMAKE_BINARY_DIR}/bin/src");
+        assert_eq!(in_binary_dir("bin/my tools"), "\"${CMAKE_BINARY_DIR}/bin/my tools\"");
+    }
+
+    #[test]
+    fn splits_the_preprocessor_flags() {
+        let flags: Vec<String> = ["-DNDEBUG", "-UTRACE", "-iquote quote", "-Iinc", "-isystem sys"].iter().map(|flag| flag.to_string()).collect();
+        let settings = PreprocessorSettings::from(&flags);
+        assert_eq!(settings.definitions, vec!["NDEBUG".to_string()]);
+        assert_eq!(settings.options, vec!["-UTRACE".to_string()]);
+        assert_eq!(settings.includes, vec!["quote".to_string(), "inc".to_string()]);
+        assert_eq!(settings.system_includes, vec!["sys".to_string()]);
+    }
+
+    #[test]
+    fn every_executable_is_a_target() {
+        let project = project(
+            &["-DNDEBUG", "-Iinc"],
+            &[("src/server.c", &["net/io.c"]), ("src/main.c", &[]), ("tools/main.c", &[])],
+        );
+        let lists = CMake.generate(&project, CMAKE_LISTS);
+        assert!(lists.contains(
+            "add_executable(src_server src/server.c net/io.c)\n\
+             set_target_properties(src_server PROPERTIES OUTPUT_NAME server RUNTIME_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/bin/src)\n\
+             target_compile_options(src_server PRIVATE -Wall -g)\n\
+             target_compile_definitions(src_server PRIVATE NDEBUG)\n\
+             target_include_directories(src_server PRIVATE inc)\n"
+        ));
+        // Each in its own directory, like with make
+        assert!(lists.contains(
+            "add_executable(src_main src/main.c)\n\
+             set_target_properties(src_main PROPERTIES OUTPUT_NAME main RUNTIME_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/bin/src)\n"
+        ));
+        assert!(lists.contains(
+            "add_executable(tools_main tools/main.c)\n\
+             set_target_properties(tools_main PROPERTIES OUTPUT_NAME main RUNTIME_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/bin/tools)\n"
+        ));
+    }
+
+    #[test]
+    fn executables_link_the_libraries_instead_of_their_files() {
+        let mut project = project(&[], &[("main.c", &["net/io.c", "core/log.c"])]);
+        project.set_libraries(vec![Library {
+            name: "core".to_string(),
+            kind: LibraryKind::Shared,
+            version: Some("1.2.0".to_string()),
+            sources: vec!["core/log.c".to_string()],
+        }]);
+        let lists = CMake.generate(&project, CMAKE_LISTS);
+        assert!(lists.contains("add_library(core SHARED core/log.c)\nset_target_properties(core PROPERTIES VERSION 1.2.0 SOVERSION 1)\n"));
+        assert!(lists.contains("add_executable(main main.c net/io.c)\nset_target_properties(main PROPERTIES RUNTIME_OUTPUT_DIRECTORY ${CMAKE_BINARY_DIR}/bin)\n"));
+        assert!(lists.contains("target_link_libraries(main PRIVATE core)\n"));
+    }
+}
@@ -0,0 +1,95 @@
+use std::env;
+
+use serde::Serialize;
+
+use super::Generator;
+use crate::maker::normalize;
+use crate::utils::utilities::{build_path, Makefile, BUILD_DIR};
+
+/// Where clangd and clang-tidy look for the compilation database
+pub static COMPILE_COMMANDS: &str = "compile_commands.json";
+
+/// The compilation database: how the Makefile compiles every C file
+#[derive(Debug)]
+pub struct CompileCommands;
+
+/// An entry of the database, with absolute paths so it can be read from anywhere
+#[derive(Debug, Serialize)]
+struct CompileCommand {
+    directory: String,
+    file: String,
+    arguments: Vec<String>,
+    output: String,
+}
+
+impl Generator for CompileCommands {
+    fn default_output(&self) -> &'static str {
+        COMPILE_COMMANDS
+    }
+
+    fn generate(&self, project: &Makefile, _output: &str) -> String {
+        let directory = env::current_dir().unwrap_or_default();
+        // "-iquote DIR" and "-isystem DIR" are two arguments of the compiler
+        let cpp_flags: Vec<String> = project
+            .cpp_flags()
+            .iter()
+            .flat_map(|flag| match flag.split_once(' ') {
+                Some((option, dir)) if option == "-iquote" || option == "-isystem" => {
+                    vec![option.to_string(), dir.to_string()]
+                }
+                _ => vec![flag.clone()],
+            })
+            .collect();
+        let commands: Vec<CompileCommand> = project
+            .object_cflags()
+            .into_iter()
+            .map(|(source, cflags)| {
+                // Like the Makefile: $(CC) $(CPPFLAGS) $(CFLAGS) -c $< -o $@
+                let output = format!("{}/{}.o", BUILD_DIR, build_path(&source));
+                let mut arguments = vec![project.c_compiler().to_string()];
+                arguments.extend(cpp_flags.iter().cloned());
+                arguments.extend(cflags);
+                arguments.extend(vec!["-c".to_string(), source.clone(), "-o".to_string(), output.clone()]);
+                CompileCommand {
+                    directory: directory.to_string_lossy().to_string(),
+                    file: normalize(&directory.join(&source).to_string_lossy()),
+                    arguments,
+                    output,
+                }
+            })
+            .collect();
+        let mut json = serde_json::to_string_pretty(&commands).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::tests::project;
+    use crate::config::ExecutableConfig;
+
+    #[test]
+    fn every_c_file_is_compiled_like_the_makefile_does() {
+        let mut project = project(&["-DNDEBUG", "-iquote quote"], &[("main.c", &["util.c"]), ("tools/run.c", &[])]);
+        let run = ExecutableConfig { source: "tools/run.c".to_string(), cflags: Some(vec!["-O2".to_string()]), ldflags: None, ldlibs: None };
+        project.link_with(Vec::new(), Vec::new(), vec![run]);
+        let database: serde_json::Value = serde_json::from_str(&CompileCommands.generate(&project, COMPILE_COMMANDS)).unwrap();
+        let commands = database.as_array().unwrap();
+        assert_eq!(commands.len(), 3);
+        let directory = env::current_dir().unwrap().to_string_lossy().to_string();
+        let main = &commands[0];
+        assert_eq!(main["directory"], directory.as_str());
+        assert_eq!(main["file"], format!("{}/main.c", directory).as_str());
+        assert_eq!(main["output"], "build/main.o");
+        assert_eq!(
+            main["arguments"],
+            serde_json::json!(["gcc", "-DNDEBUG", "-iquote", "quote", "-Wall", "-g", "-c", "main.c", "-o", "build/main.o"])
+        );
+        assert_eq!(
+            commands[1]["arguments"],
+            serde_json::json!(["gcc", "-DNDEBUG", "-iquote", "quote", "-O2", "-c", "tools/run.c", "-o", "build/tools/run.o"])
+        );
+    }
+}
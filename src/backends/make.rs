@@ -0,0 +1,17 @@
+use super::Generator;
+use crate::cli::DEFAULT_OUTPUT;
+use crate::utils::utilities::Makefile;
+
+/// GNU make, the Makefile nofile was named after
+#[derive(Debug)]
+pub struct Make;
+
+impl Generator for Make {
+    fn default_output(&self) -> &'static str {
+        DEFAULT_OUTPUT
+    }
+
+    fn generate(&self, project: &Makefile, _output: &str) -> String {
+        project.format()
+    }
+}
@@ -0,0 +1,78 @@
+use crate::utils::utilities::Makefile;
+
+pub mod cmake;
+pub mod compile_commands;
+pub mod make;
+pub mod ninja;
+
+/// Writes the build description of a scanned project for some build system
+pub trait Generator {
+    /// The file written when no other is asked for
+    fn default_output(&self) -> &'static str;
+
+    /// The build description of the project, to be written to output
+    /// (which the build files regenerating themselves need to know)
+    fn generate(&self, project: &Makefile, output: &str) -> String;
+}
+
+/// The build systems nofile writes for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Make,
+    CMake,
+    Ninja,
+    /// Not a build system but the compilation database of the project, asked for with --compile-commands
+    CompileCommands,
+}
+
+impl Backend {
+    /// Parses the value of --backend
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "make" => Some(Backend::Make),
+            "cmake" => Some(Backend::CMake),
+            "ninja" => Some(Backend::Ninja),
+            _ => None,
+        }
+    }
+
+    pub fn generator(self) -> Box<dyn Generator> {
+        match self {
+            Backend::Make => Box::new(make::Make),
+            Backend::CMake => Box::new(cmake::CMake),
+            Backend::Ninja => Box::new(ninja::Ninja),
+            Backend::CompileCommands => Box::new(compile_commands::CompileCommands),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use chashmap::CHashMap;
+    use crate::utils::utilities::StrPath;
+
+    /// The scanned project of the executables, each linked from the given C files
+    pub(super) fn project(cpp_flags: &[&str], executables: &[(&str, &[&str])]) -> Makefile {
+        let dependencies = CHashMap::new();
+        executables.iter().for_each(|(source, _)| {
+            dependencies.insert(StrPath::new(source.to_string()), HashSet::new());
+        });
+        let source_files = executables.iter().map(|(source, _)| StrPath::new(source.to_string())).collect();
+        let flags = |flags: &[&str]| flags.iter().map(|flag| flag.to_string()).collect();
+        let project = Makefile::new("gcc".to_string(), flags(&["-Wall", "-g"]), flags(cpp_flags), source_files, dependencies);
+        executables.iter().for_each(|(source, files)| {
+            files.iter().for_each(|file| project.add_dependency(source, file.to_string(), false));
+        });
+        project
+    }
+
+    #[test]
+    fn every_backend_has_a_name() {
+        assert_eq!(Backend::parse("make"), Some(Backend::Make));
+        assert_eq!(Backend::parse("cmake"), Some(Backend::CMake));
+        assert_eq!(Backend::parse("ninja"), Some(Backend::Ninja));
+        assert_eq!(Backend::parse("scons"), None);
+    }
+}
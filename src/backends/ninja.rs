@@ -0,0 +1,266 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::Path;
+
+use super::Generator;
+use crate::config::{LibraryKind, CONFIG_FILE};
+use crate::utils::utilities::{build_path, Library, Linking, Makefile, BUILD_DIR};
+
+/// Where ninja looks for the project
+pub static BUILD_NINJA: &str = "build.ninja";
+
+/// Ninja, which starts and checks what changed much faster than make on large trees
+#[derive(Debug)]
+pub struct Ninja;
+
+/// The rules every build file has: objects are compiled with a depfile which ninja reads
+/// (and deletes) to know their headers, executables and libraries are linked from the objects
+static RULES: &str = "\
+rule cc
+  command = $cc -MMD -MF $out.d $cppflags $cflags -c $in -o $out
+  depfile = $out.d
+  deps = gcc
+  description = CC $out
+
+rule link
+  command = $cc $cflags $ldflags $in $libs $ldlibs -o $out
+  description = LINK $out
+
+rule ar
+  command = rm -f $out && $ar rcs $out $in
+  description = AR $out
+
+rule shared
+  command = $cc $cflags -shared -Wl,-soname,$soname $ldflags $in $ldlibs -o $out
+  description = LINK $out
+
+rule symlink
+  command = ln -sf $target $out
+  description = LN $out
+";
+
+/// Escapes a path of a build statement, where spaces and colons separate paths
+fn escape_path(path: &str) -> String {
+    path.replace('$', "$$").replace(' ', "$ ").replace(':', "$:")
+}
+
+/// Escapes the value of a variable
+fn escape(value: &str) -> String {
+    value.replace('$', "$$")
+}
+
+fn paths(paths: &[String]) -> String {
+    paths.iter().map(|path| escape_path(path)).collect::<Vec<String>>().join(" ")
+}
+
+fn flags(flags: &[String]) -> String {
+    escape(&flags.join(" "))
+}
+
+/// Quotes a command line argument for the shell when it needs to be
+fn shell_quote(argument: &str) -> String {
+    let plain = !argument.is_empty()
+        && argument
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=./,:@%".contains(c));
+    if plain {
+        argument.to_string()
+    } else {
+        format!("'{}'", argument.replace('\'', "'\\''"))
+    }
+}
+
+/// A path in the build directory, escaped
+fn in_build(path: &str) -> String {
+    format!("$builddir/{}", escape_path(path))
+}
+
+fn object_of(source: &str) -> String {
+    in_build(&format!("{}.o", build_path(source)))
+}
+
+impl Ninja {
+    /// The files a library is made of: the one the executables link, and for versioned
+    /// shared objects the soname symlink and the real file
+    fn library(out: &mut String, library: &Library) {
+        let objects: Vec<String> = library.sources.iter().map(|source| object_of(source)).collect();
+        let lib = |name: &str| in_build(&format!("lib/{}", name));
+        let file = lib(&library.file_name());
+        match (library.kind, &library.version) {
+            (LibraryKind::Static, _) => {
+                out.push_str(format!("build {}: ar {}\n", file, objects.join(" ")).as_str());
+            }
+            (LibraryKind::Shared, None) => {
+                out.push_str(format!("build {}: shared {}\n", file, objects.join(" ")).as_str());
+                out.push_str(format!("  soname = {}\n", escape(&library.file_name())).as_str());
+            }
+            (LibraryKind::Shared, Some(version)) => {
+                // libcore.so -> libcore.so.1 (the soname) -> libcore.so.1.2.0 (the real file)
+                let major = version.split('.').next().unwrap_or(version);
+                let soname = format!("{}.{}", library.file_name(), major);
+                let real_name = format!("{}.{}", library.file_name(), version);
+                out.push_str(format!("build {}: shared {}\n", lib(&real_name), objects.join(" ")).as_str());
+                out.push_str(format!("  soname = {}\n", escape(&soname)).as_str());
+                out.push_str(format!("build {}: symlink {}\n", lib(&soname), lib(&real_name)).as_str());
+                out.push_str(format!("  target = {}\n", escape(&real_name)).as_str());
+                out.push_str(format!("build {}: symlink {}\n", file, lib(&soname)).as_str());
+                out.push_str(format!("  target = {}\n", escape(&soname)).as_str());
+            }
+        }
+        out.push('\n');
+    }
+
+    /// Reruns nofile the way it was run, when one of the files it scanned changes
+    fn regen(out: &mut String, project: &Makefile, output: &str) {
+        // Printing the build file instead of writing it would regenerate it forever
+        let command: Vec<String> = env::args()
+            .filter(|arg| arg != "--stdout" && arg != "--dry-run")
+            .map(|arg| shell_quote(&arg))
+            .collect();
+        let mut scanned: BTreeSet<String> = project.translation_units().into_iter().collect();
+        project.includes().iter().for_each(|include| {
+            scanned.insert(include.including.clone());
+            scanned.insert(include.included.clone());
+        });
+        if Path::new(CONFIG_FILE).exists() {
+            scanned.insert(CONFIG_FILE.to_string());
+        }
+        let scanned: Vec<String> = scanned.into_iter().collect();
+        out.push_str(
+            format!(
+                "rule regen\n  command = {}\n  generator = 1\n  description = Regenerating $out\n\n",
+                escape(&command.join(" "))
+            )
+            .as_str(),
+        );
+        out.push_str(format!("build {}: regen | {}\n\n", escape_path(output), paths(&scanned)).as_str());
+    }
+}
+
+impl Generator for Ninja {
+    fn default_output(&self) -> &'static str {
+        BUILD_NINJA
+    }
+
+    fn generate(&self, project: &Makefile, output: &str) -> String {
+        let mut out = String::from("ninja_required_version = 1.3\n");
+        out.push_str(format!("builddir = {}\n", BUILD_DIR).as_str());
+        out.push_str(format!("cc = {}\n", escape(project.c_compiler())).as_str());
+        if project.libraries().iter().any(|library| library.kind == LibraryKind::Static) {
+            out.push_str("ar = ar\n");
+        }
+        out.push_str(format!("cflags = {}\n", flags(project.c_flags())).as_str());
+        out.push_str(format!("cppflags = {}\n", flags(project.cpp_flags())).as_str());
+        out.push_str(format!("ldflags = {}\n", flags(project.ld_flags())).as_str());
+        out.push_str(format!("ldlibs = {}\n\n", flags(project.ld_libs())).as_str());
+        out.push_str(RULES);
+        out.push('\n');
+        Ninja::regen(&mut out, project, output);
+
+        // Only the objects compiled with other CFLAGS than the project's say so
+        project.object_cflags().iter().for_each(|(source, cflags)| {
+            out.push_str(format!("build {}: cc {}\n", object_of(source), escape_path(source)).as_str());
+            if cflags.as_slice() != project.c_flags() {
+                out.push_str(format!("  cflags = {}\n", flags(cflags)).as_str());
+            }
+        });
+        out.push('\n');
+
+        let mut defaults: Vec<String> = Vec::new();
+        project.libraries().iter().for_each(|library| {
+            Ninja::library(&mut out, library);
+            defaults.push(in_build(&format!("lib/{}", library.file_name())));
+        });
+
+        project.linkings().into_iter().for_each(|linking| {
+            let Linking { source, sources, conditional, libraries: used, rpath } = &linking;
+            let exe = in_build(&format!("bin/{}", build_path(source)));
+            out.push_str(&linking.conditional_comment());
+            let objects: Vec<String> = std::iter::once(source)
+                .chain(sources.iter())
+                .chain(conditional.iter())
+                .map(|file| object_of(file))
+                .collect();
+            let library_files: Vec<String> =
+                used.iter().map(|library| in_build(&format!("lib/{}", library.file_name()))).collect();
+            let implicit = if library_files.is_empty() { String::new() } else { format!(" | {}", library_files.join(" ")) };
+            out.push_str(format!("build {}: link {}{}\n", exe, objects.join(" "), implicit).as_str());
+            if !used.is_empty() {
+                let mut libs = String::from("-L$builddir/lib");
+                used.iter().for_each(|library| libs.push_str(format!(" -l{}", escape(&library.name)).as_str()));
+                if let Some(rpath) = rpath {
+                    libs.push_str(format!(" {}", rpath).as_str());
+                }
+                out.push_str(format!("  libs = {}\n", libs).as_str());
+            }
+            if let Some(config) = project.executable_config(source) {
+                // Its CFLAGS went to its objects
+                [("ldflags", &config.ldflags), ("ldlibs", &config.ldlibs)]
+                    .iter()
+                    .for_each(|(variable, value)| {
+                        if let Some(value) = value {
+                            out.push_str(format!("  {} = {}\n", variable, flags(value)).as_str());
+                        }
+                    });
+            }
+            out.push('\n');
+            defaults.push(exe);
+        });
+        out.push_str(format!("default {}\n", defaults.join(" ")).as_str());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::tests::project;
+
+    #[test]
+    fn escapes_what_ninja_and_the_shell_would_split() {
+        assert_eq!(escape_path("my dir/a:b$.c"), "my$ dir/a$:b$$.c");
+        assert_eq!(shell_quote("-DLEVEL=2"), "-DLEVEL=2");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn objects_are_compiled_with_depfiles_and_linked() {
+        let project = project(&["-Iinc"], &[("src/server.c", &["net/io.c"]), ("tools/main.c", &[])]);
+        let ninja = Ninja.generate(&project, BUILD_NINJA);
+        assert!(ninja.contains("cppflags = -Iinc\n"));
+        assert!(ninja.contains("  depfile = $out.d\n  deps = gcc\n"));
+        assert!(ninja.contains("build $builddir/net/io.o: cc net/io.c\nbuild $builddir/src/server.o: cc src/server.c\n"));
+        assert!(ninja.contains("build $builddir/bin/src/server: link $builddir/src/server.o $builddir/net/io.o\n"));
+        assert!(ninja.ends_with("default $builddir/bin/src/server $builddir/bin/tools/main\n"));
+    }
+
+    #[test]
+    fn the_build_file_regenerates_when_a_scanned_file_changes() {
+        let project = project(&[], &[("main.c", &["util.c"])]);
+        let ninja = Ninja.generate(&project, "out.ninja");
+        assert!(ninja.contains("  generator = 1\n"));
+        assert!(ninja.contains("build out.ninja: regen | main.c util.c"));
+    }
+
+    #[test]
+    fn versioned_shared_libraries_have_their_symlinks() {
+        let mut project = project(&[], &[("src/server.c", &["core/log.c"])]);
+        project.set_libraries(vec![Library {
+            name: "core".to_string(),
+            kind: LibraryKind::Shared,
+            version: Some("1.2.0".to_string()),
+            sources: vec!["core/log.c".to_string()],
+        }]);
+        let ninja = Ninja.generate(&project, BUILD_NINJA);
+        assert!(ninja.contains("build $builddir/core/log.o: cc core/log.c\n  cflags = -Wall -g -fPIC\n"));
+        assert!(ninja.contains(
+            "build $builddir/lib/libcore.so.1.2.0: shared $builddir/core/log.o\n  soname = libcore.so.1\n\
+             build $builddir/lib/libcore.so.1: symlink $builddir/lib/libcore.so.1.2.0\n  target = libcore.so.1.2.0\n\
+             build $builddir/lib/libcore.so: symlink $builddir/lib/libcore.so.1\n  target = libcore.so.1\n"
+        ));
+        assert!(ninja.contains(
+            "build $builddir/bin/src/server: link $builddir/src/server.o | $builddir/lib/libcore.so\n\
+             \x20 libs = -L$builddir/lib -lcore -Wl,-rpath,'$$ORIGIN/../../lib'\n"
+        ));
+    }
+}
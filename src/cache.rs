@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+use chashmap::CHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::lexer::Directive;
+use crate::symbols::Symbols;
+
+/// Where nofile keeps what it learnt about the files between runs
+pub static CACHE_DIR: &str = ".nofile";
+pub static CACHE_FILE: &str = ".nofile/cache";
+
+/// Changes whenever what is stored changes, so the caches of older versions are ignored
+static CACHE_VERSION: u32 = 2;
+
+/// What is known about a file, valid as long as its contents stay the same
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileEntry {
+    /// Seconds and nanoseconds since the epoch
+    modified: (u64, u32),
+    size: u64,
+    /// FNV-1a of the contents, for files touched without being changed
+    hash: u64,
+    directives: Option<Vec<Directive>>,
+    symbols: Option<Symbols>,
+}
+
+/// The contents of the cache file
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    version: u32,
+    /// The macros and the search paths of the run which wrote the cache
+    fingerprint: String,
+    /// By normalised path
+    files: HashMap<String, FileEntry>,
+}
+
+/// The files parsed by the previous runs, and the ones parsed (or checked) by this one
+#[derive(Debug, Default)]
+pub struct ScanCache {
+    fingerprint: String,
+    previous: HashMap<String, FileEntry>,
+    current: CHashMap<String, FileEntry>,
+    parsed: AtomicUsize,
+    reused: AtomicUsize,
+}
+
+/// FNV-1a, which is enough to tell whether a file changed and is the same for every build of nofile
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The modification time and the size of the file, None when there is no file
+fn stat(path: &str) -> Option<((u64, u32), u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(((modified.as_secs(), modified.subsec_nanos()), metadata.len()))
+}
+
+impl ScanCache {
+    /// Reads the cache file. A missing or unreadable cache, one written by another version of nofile
+    /// or for other macros or search paths (another fingerprint) gives an empty cache
+    pub fn load(fingerprint: String) -> Self {
+        let previous = fs::read_to_string(CACHE_FILE)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Stored>(&contents).ok())
+            .filter(|stored| stored.version == CACHE_VERSION && stored.fingerprint == fingerprint)
+            .map(|stored| stored.files)
+            .unwrap_or_default();
+        ScanCache {
+            fingerprint,
+            previous,
+            ..ScanCache::default()
+        }
+    }
+
+    /// How many files were parsed, and how many were taken from the cache
+    pub fn stats(&self) -> (usize, usize) {
+        (self.parsed.load(Ordering::Relaxed), self.reused.load(Ordering::Relaxed))
+    }
+
+    /// The directives of the file at the normalised path, parsed only when the file changed since
+    /// it was cached. contents gives the file when it was already read
+    pub fn directives(
+        &self,
+        path: &str,
+        contents: Option<&str>,
+        parse: impl FnOnce(&str) -> Vec<Directive>,
+    ) -> Option<Vec<Directive>> {
+        self.lookup(path, contents, |entry| &mut entry.directives, parse)
+    }
+
+    /// The symbols of the file at the normalised path, extracted only when the file changed
+    /// since it was cached
+    pub fn symbols(&self, path: &str, extract: impl FnOnce(&str) -> Symbols) -> Option<Symbols> {
+        self.lookup(path, None, |entry| &mut entry.symbols, extract)
+    }
+
+    fn lookup<T: Clone>(
+        &self,
+        path: &str,
+        contents: Option<&str>,
+        field: impl Fn(&mut FileEntry) -> &mut Option<T>,
+        compute: impl FnOnce(&str) -> T,
+    ) -> Option<T> {
+        let (modified, size) = stat(path)?;
+        let known = self
+            .current
+            .get(path)
+            .map(|entry| entry.clone())
+            .or_else(|| self.previous.get(path).cloned());
+        // Same time and size: the file was not touched
+        let untouched = known
+            .clone()
+            .filter(|entry| entry.modified == modified && entry.size == size);
+        if let Some(value) = untouched.clone().and_then(|mut entry| field(&mut entry).clone()) {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            return Some(value);
+        }
+        let read;
+        let contents = match contents {
+            Some(contents) => contents,
+            None => {
+                read = fs::read_to_string(path).ok()?;
+                read.as_str()
+            }
+        };
+        let hash = hash(contents.as_bytes());
+        // Touched but the same contents: what is known still holds
+        let mut entry = match untouched.or(known).filter(|entry| entry.hash == hash && entry.size == size) {
+            Some(entry) => entry,
+            None => FileEntry {
+                modified,
+                size,
+                hash,
+                directives: None,
+                symbols: None,
+            },
+        };
+        entry.modified = modified;
+        let value = match field(&mut entry).clone() {
+            Some(value) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                value
+            }
+            None => {
+                self.parsed.fetch_add(1, Ordering::Relaxed);
+                compute(contents)
+            }
+        };
+        *field(&mut entry) = Some(value.clone());
+        // Another worker may have looked at the file meanwhile: keep what both found
+        self.current.alter(path.to_string(), |current| match current {
+            Some(mut current) if current.hash == entry.hash => {
+                if field(&mut current).is_none() {
+                    *field(&mut current) = Some(value.clone());
+                }
+                Some(current)
+            }
+            _ => Some(entry),
+        });
+        Some(value)
+    }
+
+    /// Writes the cache file: the files seen by this run, and the ones of the previous runs which
+    /// were not looked at (they are checked when they are).
+    /// The file is replaced at once, so concurrent runs never read half a cache
+    pub fn save(self) -> io::Result<()> {
+        let mut files = self.previous;
+        files.extend(self.current);
+        let stored = Stored {
+            version: CACHE_VERSION,
+            fingerprint: self.fingerprint,
+            files,
+        };
+        let contents = serde_json::to_string(&stored).unwrap_or_default();
+        fs::create_dir_all(CACHE_DIR)?;
+        let temporary = format!("{}.{}", CACHE_FILE, process::id());
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, CACHE_FILE).inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    /// A file of its own in the temporary directory, with the given contents
+    fn temporary(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("nofile-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn parse(contents: &str) -> Vec<Directive> {
+        contents
+            .lines()
+            .enumerate()
+            .map(|(line, path)| Directive {
+                name: "include".to_string(),
+                body: format!("\"{}\"", path),
+                line: line + 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hashes_like_fnv_1a() {
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(hash(b"ab"), hash(b"ba"));
+    }
+
+    #[test]
+    fn parses_a_file_once() {
+        let path = temporary("once.h", "a.h\n");
+        let cache = ScanCache::default();
+        assert_eq!(cache.directives(&path, None, parse).map(|directives| directives.len()), Some(1));
+        let again = cache.directives(&path, None, |_| panic!("parsed again"));
+        assert_eq!(again.map(|directives| directives.len()), Some(1));
+        assert_eq!(cache.stats(), (1, 1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_touched_file_with_the_same_contents_is_not_parsed_again() {
+        let path = temporary("touched.h", "a.h\n");
+        let cache = ScanCache::default();
+        cache.directives(&path, None, parse);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let again = cache.directives(&path, None, |_| panic!("parsed again"));
+        assert_eq!(again.map(|directives| directives.len()), Some(1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_changed_file_is_parsed_again() {
+        let path = temporary("changed.h", "a.h\n");
+        let cache = ScanCache::default();
+        cache.directives(&path, None, parse);
+        fs::write(&path, "a.h\nb.h\n").unwrap();
+        assert_eq!(cache.directives(&path, None, parse).map(|directives| directives.len()), Some(2));
+        assert_eq!(cache.stats(), (2, 0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_has_nothing() {
+        let cache = ScanCache::default();
+        assert!(cache.directives("/nonexistent/nofile/a.h", None, parse).is_none());
+    }
+}
@@ -0,0 +1,561 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::backends::compile_commands::COMPILE_COMMANDS;
+use crate::backends::Backend;
+use crate::graph::GraphFormat;
+use crate::maker::{self, *};
+use crate::preprocessor::UnknownMacros;
+use crate::utils::diagnostics::DiagnosticsFormat;
+use crate::utils::errors::*;
+use crate::utils::utilities::Verbosity;
+
+/// Where the Makefile is written when no -o is given
+pub static DEFAULT_OUTPUT: &str = "_Makefile";
+
+pub static USAGE: &str = "\
+Usage: nofile [COMMAND] [OPTIONS] <start1.c> <start2.c> ...
+
+Commands:
+    generate        Writes the Makefile (or what --backend says) of the executables (the default)
+    graph           Prints the C files linked into every executable, or with --format the include graph too
+    check           Scans the project and reports problems, without writing anything
+    explain         Prints why every C file is linked into the executable: explain <start.c> [<file.c> ...]
+    init            Writes a nofile.toml for the given executables
+    clean           Removes what generate writes, the build directory and the scan cache
+
+Options:
+    -o, --output FILE                   Where to write the Makefile (default _Makefile)
+    -b, --backend make|cmake|ninja      What to write: a Makefile, a CMakeLists.txt, a build.ninja or,
+                                        given several times or as a list (make,ninja), several of them
+        --discover [DIR]                Builds every C file defining main under DIR (default .)
+        --discover-include DIR|GLOB     Only looks for main under DIR or in the files matching GLOB
+        --discover-exclude DIR|GLOB     Never looks for main under DIR or in the files matching GLOB
+        --format text|dot|json|mermaid  How graph prints the graph (Graphviz, JSON or Mermaid for the include graph)
+        --compile-commands              Writes compile_commands.json too, for clangd and clang-tidy
+        --stdout                        Writes the Makefile to the standard output instead
+        --dry-run                       Does everything but writing or removing files
+        --cc COMPILER                   The C compiler of the Makefile
+        --cflags \"FLAGS\"                Replaces the CFLAGS of the Makefile
+    -D NAME[=VALUE], -U NAME            Defines or undefines a macro, like gcc does
+    -I DIR, -iquote DIR, -isystem DIR   Adds an include search path, like gcc does
+        --unknown-macros false|true|both
+        --link headers|symbols
+        --header-deps explicit|depfiles
+    -j, --jobs N                        How many threads follow the includes (default: one per core)
+        --no-cache                      Parses every file again instead of using .nofile/cache
+        --strict                        Fails when the includes go around in a cycle or a \"quoted\"
+                                        include is found nowhere
+    -q, --quiet                         Only prints warnings and errors
+    -v, --verbose                       Prints what was found and written as well
+        --color auto|always|never
+        --diagnostics human|json|sarif  How warnings and errors are reported: in color (the default),
+                                        as JSON lines or as a SARIF 2.1.0 log
+        --diagnostics-output FILE       Writes the JSON or SARIF diagnostics to FILE instead of the standard error
+    -h, --help                          Prints this help
+
+Arguments can be read from files with @FILE, and the C files from the standard input with -
+(both separated by whitespace). Without C files, the executables of nofile.toml are used.
+
+Exit status: 0 when everything went fine, 1 when check found problems, 2 for a bad command line,
+3 for a bad nofile.toml, 4 when a file could not be read or written and 5 when the scan found errors.";
+
+/// What nofile was asked to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Generate,
+    Graph,
+    Check,
+    Explain,
+    Init,
+    Clean,
+}
+
+impl Command {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "generate" => Some(Command::Generate),
+            "graph" => Some(Command::Graph),
+            "check" => Some(Command::Check),
+            "explain" => Some(Command::Explain),
+            "init" => Some(Command::Init),
+            "clean" => Some(Command::Clean),
+            _ => None,
+        }
+    }
+}
+
+/// When to print in color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only when the output is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether the messages, which go to the standard error, get colors
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => std::env::var_os("NO_COLOR").is_none() && termion::is_tty(&io::stderr()),
+        }
+    }
+}
+
+/// The parsed command line
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    /// The C files given, the entrypoints of the executables for most commands
+    pub files: Vec<String>,
+    /// Where to look for the entrypoints defining main
+    pub discover: Option<String>,
+    pub options: ScanOptions,
+    /// What to write, in order
+    pub backends: Vec<Backend>,
+    /// Where to write it, when there is only one backend
+    pub output: Option<String>,
+    /// Whether to write compile_commands.json as well
+    pub compile_commands: bool,
+    /// What the graph command prints
+    pub graph_format: GraphFormat,
+    /// How the warnings and the errors are reported
+    pub diagnostics: DiagnosticsFormat,
+    /// Where they are written in the formats which are not for people
+    pub diagnostics_output: Option<String>,
+    /// Whether the scan reuses (and saves) what the previous runs parsed
+    pub cache: bool,
+    pub stdout: bool,
+    pub dry_run: bool,
+    pub verbosity: Verbosity,
+    pub color: ColorChoice,
+    pub help: bool,
+}
+
+/// Parses the arguments (the first one being the executable name), starting from the given options
+pub fn parse(args: Vec<String>, options: ScanOptions) -> Result<Cli, NFError> {
+    let mut cli = Cli {
+        command: Command::Generate,
+        files: Vec::new(),
+        discover: None,
+        options,
+        backends: Vec::new(),
+        output: None,
+        compile_commands: false,
+        graph_format: GraphFormat::Text,
+        diagnostics: DiagnosticsFormat::Human,
+        diagnostics_output: None,
+        cache: true,
+        stdout: false,
+        dry_run: false,
+        verbosity: Verbosity::Normal,
+        color: ColorChoice::Auto,
+        help: false,
+    };
+    let mut arg_iter = expand(args.into_iter().skip(1).collect())?.into_iter().peekable();
+    let options = &mut cli.options;
+
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                cli.help = true;
+                continue;
+            }
+            "-q" | "--quiet" => {
+                cli.verbosity = Verbosity::Quiet;
+                continue;
+            }
+            "-v" | "--verbose" => {
+                cli.verbosity = Verbosity::Verbose;
+                continue;
+            }
+            "--no-cache" => {
+                cli.cache = false;
+                continue;
+            }
+            "--strict" => {
+                options.strict = true;
+                continue;
+            }
+            "--compile-commands" => {
+                cli.compile_commands = true;
+                continue;
+            }
+            "--stdout" => {
+                cli.stdout = true;
+                continue;
+            }
+            "--dry-run" => {
+                cli.dry_run = true;
+                continue;
+            }
+            _ => {}
+        }
+        // Options take their value either glued ("-DNAME", "--cc=clang") or as the next argument
+        if let Some(pattern) = long_option(&arg, "--discover-include") {
+            let pattern = option_value(pattern, &arg, &mut arg_iter)?;
+            options.discovery.include.push(pattern);
+            continue;
+        }
+        if let Some(pattern) = long_option(&arg, "--discover-exclude") {
+            let pattern = option_value(pattern, &arg, &mut arg_iter)?;
+            options.discovery.exclude.push(pattern);
+            continue;
+        }
+        if let Some(dir) = long_option(&arg, "--discover") {
+            // The directory is optional, the next argument is only taken when it is one
+            let dir = match dir.strip_prefix('=') {
+                Some(dir) => dir.to_string(),
+                None => arg_iter
+                    .next_if(|next| !next.starts_with('-') && Path::new(next).is_dir())
+                    .unwrap_or_else(|| ".".to_string()),
+            };
+            cli.discover = Some(dir);
+            continue;
+        }
+        if let Some(output) = long_option(&arg, "--output") {
+            cli.output = Some(option_value(output, &arg, &mut arg_iter)?);
+            continue;
+        }
+        // Never glued, "-output" being a misspelled --output
+        if arg == "-o" {
+            cli.output = Some(option_value("", &arg, &mut arg_iter)?);
+            continue;
+        }
+        let backends = long_option(&arg, "--backend");
+        if let Some(backends) = backends.or_else(|| arg.strip_prefix("-b")) {
+            let backends = option_value(backends, &arg, &mut arg_iter)?;
+            for backend in backends.split(',') {
+                let backend = Backend::parse(backend).ok_or_else(|| NFError::InvalidOption(arg.clone()))?;
+                if !cli.backends.contains(&backend) {
+                    cli.backends.push(backend);
+                }
+            }
+            continue;
+        }
+        if let Some(compiler) = long_option(&arg, "--cc") {
+            options.compiler = option_value(compiler, &arg, &mut arg_iter)?;
+            continue;
+        }
+        if let Some(cflags) = long_option(&arg, "--cflags") {
+            let cflags = option_value(cflags, &arg, &mut arg_iter)?;
+            options.cflags = cflags.split_whitespace().map(String::from).collect();
+            continue;
+        }
+        let jobs = long_option(&arg, "--jobs");
+        if let Some(jobs) = jobs.or_else(|| arg.strip_prefix("-j")) {
+            let jobs = option_value(jobs, &arg, &mut arg_iter)?;
+            options.jobs = match jobs.parse() {
+                Ok(jobs) if jobs > 0 => jobs,
+                _ => return Err(NFError::InvalidOption(arg)),
+            };
+            continue;
+        }
+        if let Some(format) = long_option(&arg, "--format") {
+            let format = option_value(format, &arg, &mut arg_iter)?;
+            cli.graph_format = GraphFormat::parse(&format).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if let Some(output) = long_option(&arg, "--diagnostics-output") {
+            let output = option_value(output, &arg, &mut arg_iter)?;
+            cli.diagnostics_output = Some(output);
+            continue;
+        }
+        if let Some(format) = long_option(&arg, "--diagnostics") {
+            let format = option_value(format, &arg, &mut arg_iter)?;
+            cli.diagnostics = DiagnosticsFormat::parse(&format).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if let Some(color) = long_option(&arg, "--color") {
+            let color = option_value(color, &arg, &mut arg_iter)?;
+            cli.color = ColorChoice::parse(&color).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if let Some(define) = arg.strip_prefix("-D") {
+            let define = option_value(define, &arg, &mut arg_iter)?;
+            options.define(&define);
+            continue;
+        }
+        if let Some(undef) = arg.strip_prefix("-U") {
+            let undef = option_value(undef, &arg, &mut arg_iter)?;
+            options.undefine(&undef);
+            continue;
+        }
+        if let Some(dir) = arg.strip_prefix("-isystem") {
+            options.system_dirs.push(option_value(dir, &arg, &mut arg_iter)?);
+            continue;
+        }
+        if let Some(dir) = arg.strip_prefix("-iquote") {
+            options.quote_dirs.push(option_value(dir, &arg, &mut arg_iter)?);
+            continue;
+        }
+        if let Some(dir) = arg.strip_prefix("-I") {
+            options.include_dirs.push(option_value(dir, &arg, &mut arg_iter)?);
+            continue;
+        }
+        if let Some(policy) = long_option(&arg, "--unknown-macros") {
+            let policy = option_value(policy, &arg, &mut arg_iter)?;
+            options.unknown_macros = UnknownMacros::parse(&policy).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if let Some(mode) = long_option(&arg, "--link") {
+            let mode = option_value(mode, &arg, &mut arg_iter)?;
+            options.link_mode = LinkMode::parse(&mode).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if let Some(deps) = long_option(&arg, "--header-deps") {
+            let deps = option_value(deps, &arg, &mut arg_iter)?;
+            options.header_deps = HeaderDeps::parse(&deps).ok_or(NFError::InvalidOption(arg))?;
+            continue;
+        }
+        if arg.starts_with('-') {
+            return Err(NFError::InvalidOption(arg));
+        }
+        // The command comes before the files
+        if cli.files.is_empty() && cli.command == Command::Generate {
+            if let Some(command) = Command::parse(&arg) {
+                cli.command = command;
+                continue;
+            }
+        }
+        cli.files.push(arg);
+    }
+    if cli.backends.is_empty() {
+        cli.backends.push(Backend::Make);
+    }
+    // One file name cannot do for several backends
+    if cli.output.is_some() && cli.backends.len() > 1 {
+        return Err(NFError::InvalidOption("--output with several backends".to_string()));
+    }
+    Ok(cli)
+}
+
+impl Cli {
+    /// Where every backend writes, the compilation database last
+    pub fn outputs(&self) -> Vec<(Backend, String)> {
+        let mut outputs: Vec<(Backend, String)> = self
+            .backends
+            .iter()
+            .map(|backend| {
+                let default = backend.generator().default_output().to_string();
+                (*backend, self.output.clone().unwrap_or(default))
+            })
+            .collect();
+        if self.compile_commands {
+            outputs.push((Backend::CompileCommands, COMPILE_COMMANDS.to_string()));
+        }
+        outputs
+    }
+}
+
+/// Replaces the @FILE arguments with the arguments in FILE,
+/// and "-" with the files named on the standard input
+fn expand(args: Vec<String>) -> Result<Vec<String>, NFError> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        if arg == "-" {
+            let mut names = String::new();
+            io::stdin()
+                .read_to_string(&mut names)
+                .map_err(|e| NFError::IOError(e, "<stdin>".to_string()))?;
+            expanded.extend(names.split_whitespace().map(String::from));
+        } else if let Some(path) = arg.strip_prefix('@') {
+            let contents = fs::read_to_string(path).map_err(|e| NFError::IOError(e, path.to_string()))?;
+            expanded.extend(contents.split_whitespace().map(String::from));
+        } else {
+            expanded.push(arg);
+        }
+    }
+    Ok(expanded)
+}
+
+/// How the diagnostics are reported according to the arguments given directly (not in @FILE),
+/// read before anything else can fail. Wrong values are left to parse, which reports them
+pub fn diagnostics_args(args: &[String]) -> (DiagnosticsFormat, Option<String>) {
+    let mut format = DiagnosticsFormat::Human;
+    let mut output = None;
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        let mut value = |glued: &str| match glued.strip_prefix('=') {
+            Some(value) => Some(value.to_string()),
+            None => arg_iter.next().cloned(),
+        };
+        if let Some(glued) = long_option(arg, "--diagnostics-output") {
+            output = value(glued);
+        } else if let Some(glued) = long_option(arg, "--diagnostics") {
+            format = value(glued).and_then(|name| DiagnosticsFormat::parse(&name)).unwrap_or(format);
+        }
+    }
+    (format, output)
+}
+
+/// What follows the long option name when arg is the name alone ("") or the name and its value
+/// ("=value"), None for any other argument: "--outputs" is not --output
+fn long_option<'a>(arg: &'a str, name: &str) -> Option<&'a str> {
+    arg.strip_prefix(name).filter(|rest| rest.is_empty() || rest.starts_with('='))
+}
+
+/// Returns the value of an option, either the part glued to it or the next argument
+fn option_value(
+    glued: &str,
+    option: &str,
+    arg_iter: &mut impl Iterator<Item = String>,
+) -> Result<String, NFError> {
+    // "--cc=" misses its value, the next argument is not taken instead
+    if let Some(value) = glued.strip_prefix('=') {
+        return match value {
+            "" => Err(NFError::MissingValue(option.to_string())),
+            value => Ok(value.to_string()),
+        };
+    }
+    if !glued.is_empty() {
+        return Ok(glued.to_string());
+    }
+    arg_iter
+        .next()
+        .ok_or_else(|| NFError::MissingValue(option.to_string()))
+}
+
+/// Reads the entrypoints of the executables: the files given and the ones discovered or,
+/// without any, the executables of the configuration file
+pub fn entrypoints(files: &[String], discover: Option<&str>, options: &ScanOptions) -> Result<Vec<(String, String)>, NFError> {
+    let mut files = files.to_vec();
+    if let Some(root) = discover {
+        files.extend(maker::discover(root, &options.discovery, &options.defines, options.unknown_macros));
+    }
+    if files.is_empty() {
+        files = options.executables.iter().map(|executable| executable.source.clone()).collect();
+    }
+    if files.is_empty() {
+        return Err(NFError::NotEnoughArgs);
+    }
+    files.into_iter().map(read_entrypoint).collect()
+}
+
+/// Reads the C file which is the entrypoint of an executable
+fn read_entrypoint(path_to_start: String) -> Result<(String, String), NFError> {
+    if !path_to_start.ends_with(".c") {
+        // not a C file
+        return Err(NFError::InvalidFileExt(path_to_start));
+    }
+    match fs::read_to_string(&path_to_start) {
+        Ok(contents) => Ok((path_to_start, contents)),
+        Err(e) => Err(NFError::IOError(e, path_to_start)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The command line of nofile with the given arguments
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("nofile").chain(args.iter().copied()).map(String::from).collect()
+    }
+
+    fn parsed(args: &[&str]) -> Result<Cli, NFError> {
+        parse(self::args(args), ScanOptions::default())
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        parsed(args).ok().expect("the arguments are valid")
+    }
+
+    #[test]
+    fn the_command_comes_before_the_files() {
+        let graph = cli(&["graph", "main.c", "check"]);
+        assert_eq!(graph.command, Command::Graph);
+        assert_eq!(graph.files, vec!["main.c".to_string(), "check".to_string()]);
+        let generate = cli(&["main.c"]);
+        assert_eq!(generate.command, Command::Generate);
+        assert_eq!(generate.files, vec!["main.c".to_string()]);
+    }
+
+    #[test]
+    fn values_are_glued_or_the_next_argument() {
+        let cli = cli(&["-o", "out.mk", "--cc=clang", "-DNDEBUG", "-D", "LEVEL=2", "-Iinc", "-I", "lib", "-iquote", "quote", "main.c"]);
+        assert_eq!(cli.output.as_deref(), Some("out.mk"));
+        assert_eq!(cli.options.compiler, "clang");
+        assert_eq!(cli.options.define_flags, vec!["-DNDEBUG".to_string(), "-DLEVEL=2".to_string()]);
+        assert_eq!(cli.options.include_dirs, vec!["inc".to_string(), "lib".to_string()]);
+        assert_eq!(cli.options.quote_dirs, vec!["quote".to_string()]);
+        assert_eq!(cli.files, vec!["main.c".to_string()]);
+    }
+
+    #[test]
+    fn reads_the_flags() {
+        let cli = cli(&["--stdout", "--dry-run", "-q", "--cflags", "-O2 -g", "--link=symbols", "main.c"]);
+        assert!(cli.stdout && cli.dry_run);
+        assert_eq!(cli.verbosity, Verbosity::Quiet);
+        assert_eq!(cli.options.cflags, vec!["-O2".to_string(), "-g".to_string()]);
+        assert_eq!(cli.options.link_mode, LinkMode::Symbols);
+        assert_eq!(cli.graph_format, GraphFormat::Text);
+        assert_eq!(parsed(&["graph", "--format", "mermaid"]).ok().map(|cli| cli.graph_format), Some(GraphFormat::Mermaid));
+    }
+
+    #[test]
+    fn unknown_options_and_missing_values_are_errors() {
+        assert!(matches!(parsed(&["--frobnicate", "main.c"]), Err(NFError::InvalidOption(_))));
+        assert!(matches!(parsed(&["--link", "magic", "main.c"]), Err(NFError::InvalidOption(_))));
+        assert!(matches!(parsed(&["graph", "--format=svg"]), Err(NFError::InvalidOption(_))));
+        assert!(matches!(parsed(&["main.c", "-o"]), Err(NFError::MissingValue(_))));
+    }
+
+    #[test]
+    fn every_backend_writes_its_own_file() {
+        let outputs = cli(&["--backend", "make,cmake", "-bmake", "main.c"]).outputs();
+        assert_eq!(outputs, vec![(Backend::Make, DEFAULT_OUTPUT.to_string()), (Backend::CMake, "CMakeLists.txt".to_string())]);
+        assert_eq!(cli(&["-o", "out.mk", "main.c"]).outputs(), vec![(Backend::Make, "out.mk".to_string())]);
+        assert!(matches!(parsed(&["-b", "make,cmake", "-o", "out", "main.c"]), Err(NFError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn the_compilation_database_is_written_last() {
+        let outputs = cli(&["--compile-commands", "-b", "ninja", "main.c"]).outputs();
+        assert_eq!(
+            outputs,
+            vec![(Backend::Ninja, "build.ninja".to_string()), (Backend::CompileCommands, COMPILE_COMMANDS.to_string())]
+        );
+    }
+
+    #[test]
+    fn reads_how_to_report_the_diagnostics() {
+        let cli = cli(&["--diagnostics=sarif", "--diagnostics-output", "nofile.sarif", "main.c"]);
+        assert_eq!(cli.diagnostics, DiagnosticsFormat::Sarif);
+        assert_eq!(cli.diagnostics_output.as_deref(), Some("nofile.sarif"));
+        assert!(matches!(parsed(&["--diagnostics", "xml", "main.c"]), Err(NFError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn misspelled_long_options_are_not_taken_for_others() {
+        for misspelled in ["--outputs=out.mk", "-output", "--ccache", "--links=symbols", "--diagnostics-outputs=x"].iter() {
+            assert!(matches!(parsed(&[misspelled, "main.c"]), Err(NFError::InvalidOption(_))), "{}", misspelled);
+        }
+        assert!(matches!(parsed(&["--cc=", "main.c"]), Err(NFError::MissingValue(_))));
+    }
+
+    #[test]
+    fn the_diagnostics_format_is_known_before_parsing() {
+        assert_eq!(diagnostics_args(&args(&["--frobnicate", "--diagnostics", "sarif"])), (DiagnosticsFormat::Sarif, None));
+        assert_eq!(
+            diagnostics_args(&args(&["--diagnostics=json", "--diagnostics-output=out.json"])),
+            (DiagnosticsFormat::Json, Some("out.json".to_string()))
+        );
+        // Wrong values are reported by parse, in the format given so far
+        assert_eq!(diagnostics_args(&args(&["--diagnostics=json", "--diagnostics", "xml"])), (DiagnosticsFormat::Json, None));
+        assert_eq!(diagnostics_args(&args(&["--diagnosticsjson"])), (DiagnosticsFormat::Human, None));
+    }
+}
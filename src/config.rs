@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+
+use serde::Deserialize;
+
+use crate::maker::{Discovery, HeaderDeps};
+use crate::preprocessor::UnknownMacros;
+use crate::utils::errors::*;
+
+/// The project configuration file, looked up in the current directory
+pub static CONFIG_FILE: &str = "nofile.toml";
+
+/// The contents of nofile.toml
+/// Example:
+/// ```toml
+/// [build]
+/// cc = "clang"
+/// cflags = ["-Wall", "-O2"]
+/// ldlibs = ["-lm"]
+/// include = ["include"]
+/// defines = ["NDEBUG", "LEVEL=2"]
+/// undefines = ["USE_GUI"]
+/// unknown_macros = "both"
+///
+/// [[executables]]
+/// source = "src/server.c"
+/// ldlibs = ["-lm", "-lpthread"]
+///
+/// [[libraries]]
+/// name = "core"
+/// kind = "shared"
+/// sources = ["src/core/**"]
+/// version = "1.2.0"
+///
+/// [discover]
+/// exclude = ["tests", "**/*_bench.c"]
+///
+/// [headers]
+/// deps = "depfiles"
+/// basename = true
+///
+/// [[headers.mirror]]
+/// from = "include/**"
+/// to = "src/**"
+///
+/// [headers.implementations]
+/// "include/foo.h" = ["src/foo_io.c", "src/foo_math.c"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub build: BuildConfig,
+    pub executables: Vec<ExecutableConfig>,
+    pub libraries: Vec<LibraryConfig>,
+    pub headers: HeadersConfig,
+    pub discover: Discovery,
+}
+
+/// How everything is compiled and linked
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildConfig {
+    /// The C compiler, gcc by default
+    pub cc: Option<String>,
+    /// Replaces the default CFLAGS
+    pub cflags: Option<Vec<String>>,
+    pub ldflags: Vec<String>,
+    pub ldlibs: Vec<String>,
+    /// Directories searched like with -I
+    pub include: Vec<String>,
+    /// Directories searched like with -iquote
+    pub iquote: Vec<String>,
+    /// Directories searched like with -isystem
+    pub isystem: Vec<String>,
+    /// Macros defined like with -D, as "NAME" or "NAME=VALUE"
+    pub defines: Vec<String>,
+    /// Macros undefined like with -U, after the defines
+    pub undefines: Vec<String>,
+    /// Like --unknown-macros: "false", "true" or "both"
+    pub unknown_macros: Option<UnknownMacros>,
+}
+
+/// An executable of the project, built when no entrypoints are given on the command line.
+/// The flags given here replace the ones of [build] for this executable only
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecutableConfig {
+    /// The C file with the main function
+    pub source: String,
+    pub cflags: Option<Vec<String>>,
+    pub ldflags: Option<Vec<String>>,
+    pub ldlibs: Option<Vec<String>>,
+}
+
+/// Whether a library is an archive or a shared object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryKind {
+    /// lib<name>.a, made by ar
+    Static,
+    /// lib<name>.so, compiled with -fPIC
+    Shared,
+}
+
+/// A library built from some of the C files, which the executables using them link
+/// instead of the objects
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryConfig {
+    pub name: String,
+    pub kind: LibraryKind,
+    /// C files, directories or globs. Without them, the library is made of
+    /// the C files which all the executables link
+    pub sources: Option<Vec<String>>,
+    /// "MAJOR.MINOR.PATCH" for shared objects: the soname is lib<name>.so.MAJOR
+    pub version: Option<String>,
+}
+
+/// How headers are mapped to the C files implementing them
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// Directory mirroring rules, tried in order
+    pub mirror: Vec<MirrorRule>,
+    /// Explicit implementations of headers, by header path
+    pub implementations: HashMap<String, Vec<String>>,
+    /// Whether a C file with the same name anywhere in the project may implement a header
+    /// nothing else maps, off by default
+    pub basename: bool,
+    /// How the generated Makefile tracks the headers of each object
+    pub deps: Option<HeaderDeps>,
+}
+
+/// Maps headers under from to C files under to, like "include/**" -> "src/**"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorRule {
+    pub from: String,
+    pub to: String,
+}
+
+impl Config {
+    /// Reads the configuration file at path.
+    /// A missing file is not an error, it just gives the default configuration
+    pub fn load(path: &str) -> Result<Config, NFError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| NFError::InvalidConfig(path.to_string(), e.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(NFError::IOError(e, path.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads the given configuration from a temporary file
+    fn load(name: &str, contents: &str) -> Result<Config, NFError> {
+        let path = std::env::temp_dir().join(format!("nofile-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        Config::load(&path.to_string_lossy())
+    }
+
+    #[test]
+    fn reads_every_section() {
+        let config = load(
+            "sections",
+            "[build]\ncc = \"clang\"\ncflags = [\"-O2\"]\ndefines = [\"LEVEL=2\"]\n\n\
+             [[executables]]\nsource = \"src/server.c\"\nldlibs = [\"-lpthread\"]\n\n\
+             [headers.implementations]\n\"include/foo.h\" = [\"src/foo_io.c\"]\n",
+        )
+        .ok()
+        .expect("the configuration is valid");
+        assert_eq!(config.build.cc.as_deref(), Some("clang"));
+        assert_eq!(config.build.cflags, Some(vec!["-O2".to_string()]));
+        assert_eq!(config.build.defines, vec!["LEVEL=2".to_string()]);
+        assert!(config.build.include.is_empty());
+        assert_eq!(config.executables[0].source, "src/server.c");
+        assert_eq!(config.executables[0].ldlibs, Some(vec!["-lpthread".to_string()]));
+        assert_eq!(config.executables[0].cflags, None);
+        assert_eq!(config.headers.implementations["include/foo.h"], vec!["src/foo_io.c".to_string()]);
+    }
+
+    #[test]
+    fn reads_the_undefines_and_how_to_treat_unknown_macros() {
+        let config = load("macros", "[build]\nundefines = [\"USE_GUI\"]\nunknown_macros = \"both\"\n")
+            .ok()
+            .expect("the configuration is valid");
+        assert_eq!(config.build.undefines, vec!["USE_GUI".to_string()]);
+        assert_eq!(config.build.unknown_macros, Some(UnknownMacros::Both));
+        assert!(matches!(load("bad-macros", "[build]\nunknown_macros = \"maybe\"\n"), Err(NFError::InvalidConfig(_, _))));
+    }
+
+    #[test]
+    fn a_missing_file_is_the_default_configuration() {
+        let config = Config::load("/nonexistent/nofile.toml").ok().expect("a missing file is no error");
+        assert!(config.build.cc.is_none());
+        assert!(config.executables.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        assert!(matches!(load("unknown", "[build]\ncompiler = \"clang\"\n"), Err(NFError::InvalidConfig(_, _))));
+        assert!(matches!(load("invalid", "[build\n"), Err(NFError::InvalidConfig(_, _))));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Serialize;
+
+use crate::maker::IncludeEdge;
+use crate::utils::utilities::{build_path, Makefile, BUILD_DIR};
+
+/// How the graph command prints the graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// The C files linked into every executable, for people
+    Text,
+    /// Graphviz
+    Dot,
+    Json,
+    Mermaid,
+}
+
+impl GraphFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(GraphFormat::Text),
+            "dot" => Some(GraphFormat::Dot),
+            "json" => Some(GraphFormat::Json),
+            "mermaid" => Some(GraphFormat::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileKind {
+    Header,
+    Source,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphFile {
+    path: String,
+    kind: FileKind,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphInclude {
+    from: String,
+    to: String,
+    line: usize,
+    conditional: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphExecutable {
+    source: String,
+    binary: String,
+    /// The C files linked into it, the entrypoint first
+    links: Vec<String>,
+    /// The C files only reached through #if branches on unknown macros
+    conditional: Vec<String>,
+}
+
+/// The include graph of the project and the C files linked into every executable
+#[derive(Debug, Serialize)]
+struct Graph {
+    files: Vec<GraphFile>,
+    includes: Vec<GraphInclude>,
+    executables: Vec<GraphExecutable>,
+}
+
+impl Graph {
+    fn from(includes: &[IncludeEdge], makefile: &Makefile) -> Self {
+        let executables: Vec<GraphExecutable> = makefile
+            .link_sets()
+            .into_iter()
+            .map(|(source, sources, conditional)| {
+                let mut links = vec![source.clone()];
+                links.extend(sources);
+                GraphExecutable {
+                    binary: format!("{}/bin/{}", BUILD_DIR, build_path(&source)),
+                    source,
+                    links,
+                    conditional,
+                }
+            })
+            .collect();
+        let mut paths: BTreeSet<&String> = BTreeSet::new();
+        includes.iter().for_each(|edge| {
+            paths.insert(&edge.including);
+            paths.insert(&edge.included);
+        });
+        executables.iter().for_each(|executable| {
+            paths.extend(executable.links.iter().chain(executable.conditional.iter()));
+        });
+        let files = paths
+            .into_iter()
+            .map(|path| GraphFile {
+                path: path.clone(),
+                kind: if path.ends_with(".h") { FileKind::Header } else { FileKind::Source },
+            })
+            .collect();
+        let includes = includes
+            .iter()
+            .map(|edge| GraphInclude {
+                from: edge.including.clone(),
+                to: edge.included.clone(),
+                line: edge.line,
+                conditional: edge.conditional,
+            })
+            .collect();
+        Graph {
+            files,
+            includes,
+            executables,
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut out = String::new();
+        self.executables.iter().for_each(|executable| {
+            out.push_str(format!("{}\n", executable.source).as_str());
+            executable.links[1..]
+                .iter()
+                .for_each(|file| out.push_str(format!("    {}\n", file).as_str()));
+            executable.conditional.iter().for_each(|file| {
+                out.push_str(format!("    {} (only through #if branches on unknown macros)\n", file).as_str());
+            });
+        });
+        out
+    }
+
+    /// Headers are notes and C files boxes, includes are labelled with their line and dashed when
+    /// conditional, executables link their C files with bold edges
+    fn dot(&self) -> String {
+        let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut out = String::from("digraph nofile {\n    rankdir=LR;\n    node [fontname=\"monospace\"];\n\n");
+        self.files.iter().for_each(|file| {
+            let shape = match file.kind {
+                FileKind::Header => "note",
+                FileKind::Source => "box",
+            };
+            out.push_str(format!("    {} [shape={}];\n", quote(&file.path), shape).as_str());
+        });
+        out.push('\n');
+        self.includes.iter().for_each(|include| {
+            let style = if include.conditional { ", style=dashed" } else { "" };
+            out.push_str(
+                format!("    {} -> {} [label=\"{}\"{}];\n", quote(&include.from), quote(&include.to), include.line, style)
+                    .as_str(),
+            );
+        });
+        self.executables.iter().for_each(|executable| {
+            out.push_str(format!("\n    {} [shape=doubleoctagon];\n", quote(&executable.binary)).as_str());
+            executable.links.iter().for_each(|file| {
+                out.push_str(format!("    {} -> {} [style=bold];\n", quote(&executable.binary), quote(file)).as_str());
+            });
+            executable.conditional.iter().for_each(|file| {
+                out.push_str(
+                    format!("    {} -> {} [style=\"bold,dashed\"];\n", quote(&executable.binary), quote(file)).as_str(),
+                );
+            });
+        });
+        out.push_str("}\n");
+        out
+    }
+
+    /// Mermaid wants plain node ids, so files are f0, f1... and executables e0, e1...
+    fn mermaid(&self) -> String {
+        let label = |name: &str| format!("\"{}\"", name.replace('"', "#quot;"));
+        let ids: HashMap<&str, usize> =
+            self.files.iter().enumerate().map(|(index, file)| (file.path.as_str(), index)).collect();
+        let id = |path: &str| format!("f{}", ids.get(path).copied().unwrap_or_default());
+        let mut out = String::from("flowchart LR\n");
+        self.files.iter().enumerate().for_each(|(index, file)| {
+            let node = match file.kind {
+                FileKind::Header => format!("[/{}/]", label(&file.path)),
+                FileKind::Source => format!("[{}]", label(&file.path)),
+            };
+            out.push_str(format!("    f{}{}\n", index, node).as_str());
+        });
+        self.includes.iter().for_each(|include| {
+            let arrow = if include.conditional { "-.->" } else { "-->" };
+            out.push_str(
+                format!("    {} {}|{}| {}\n", id(&include.from), arrow, include.line, id(&include.to)).as_str(),
+            );
+        });
+        self.executables.iter().enumerate().for_each(|(index, executable)| {
+            out.push_str(format!("    e{}([{}])\n", index, label(&executable.binary)).as_str());
+            executable.links.iter().chain(executable.conditional.iter()).for_each(|file| {
+                out.push_str(format!("    e{} ==> {}\n", index, id(file)).as_str());
+            });
+        });
+        out
+    }
+}
+
+/// The graph of the scanned project, in the given format
+pub fn render(format: GraphFormat, includes: &[IncludeEdge], makefile: &Makefile) -> String {
+    let graph = Graph::from(includes, makefile);
+    match format {
+        GraphFormat::Text => graph.text(),
+        GraphFormat::Dot => graph.dot(),
+        GraphFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&graph).unwrap_or_default();
+            json.push('\n');
+            json
+        }
+        GraphFormat::Mermaid => graph.mermaid(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use chashmap::CHashMap;
+    use crate::utils::utilities::StrPath;
+
+    /// The scanned project of the executables, each linked from the given C files
+    fn makefile(executables: &[(&str, &[&str])]) -> Makefile {
+        let dependencies = CHashMap::new();
+        executables.iter().for_each(|(source, _)| {
+            dependencies.insert(StrPath::new(source.to_string()), HashSet::new());
+        });
+        let source_files = executables.iter().map(|(source, _)| StrPath::new(source.to_string())).collect();
+        let makefile = Makefile::new("gcc".to_string(), Vec::new(), Vec::new(), source_files, dependencies);
+        executables.iter().for_each(|(source, files)| {
+            files.iter().for_each(|file| makefile.add_dependency(source, file.to_string(), false));
+        });
+        makefile
+    }
+
+    fn edge(including: &str, line: usize, included: &str, conditional: bool) -> IncludeEdge {
+        IncludeEdge {
+            including: including.to_string(),
+            line,
+            included: included.to_string(),
+            conditional,
+        }
+    }
+
+    /// main.c includes util.h, and net.h only in an #if branch on an unknown macro
+    fn graph(format: GraphFormat) -> String {
+        let includes = [edge("main.c", 1, "util.h", false), edge("main.c", 3, "net.h", true)];
+        render(format, &includes, &makefile(&[("main.c", &["util.c"])]))
+    }
+
+    #[test]
+    fn prints_the_c_files_of_every_executable() {
+        assert_eq!(graph(GraphFormat::Text), "main.c\n    util.c\n");
+    }
+
+    #[test]
+    fn dot_has_the_files_includes_and_executables() {
+        let dot = graph(GraphFormat::Dot);
+        assert!(dot.starts_with("digraph nofile {\n"));
+        assert!(dot.contains("    \"util.c\" [shape=box];\n    \"util.h\" [shape=note];\n"));
+        assert!(dot.contains("    \"main.c\" -> \"net.h\" [label=\"3\", style=dashed];\n"));
+        assert!(dot.contains("    \"build/bin/main\" [shape=doubleoctagon];\n    \"build/bin/main\" -> \"main.c\" [style=bold];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn json_has_the_files_includes_and_executables() {
+        let json: serde_json::Value = serde_json::from_str(&graph(GraphFormat::Json)).unwrap();
+        assert_eq!(json["files"].as_array().unwrap().len(), 4);
+        assert_eq!(json["files"][0], serde_json::json!({"path": "main.c", "kind": "source"}));
+        assert_eq!(json["includes"][1], serde_json::json!({"from": "main.c", "to": "net.h", "line": 3, "conditional": true}));
+        assert_eq!(
+            json["executables"][0],
+            serde_json::json!({"source": "main.c", "binary": "build/bin/main", "links": ["main.c", "util.c"], "conditional": []})
+        );
+    }
+
+    #[test]
+    fn mermaid_names_the_nodes_by_their_index() {
+        let mermaid = graph(GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("flowchart LR\n    f0[\"main.c\"]\n    f1[/\"net.h\"/]\n"));
+        assert!(mermaid.contains("    f0 -->|1| f3\n    f0 -.->|3| f1\n"));
+        assert!(mermaid.contains("    e0([\"build/bin/main\"])\n    e0 ==> f0\n    e0 ==> f2\n"));
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+
+/// The way a header was named in an #include directive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IncludeKind {
+    /// #include "header.h"
+    Quoted,
+    /// #include <header.h>
+    Angled,
+}
+
+/// An #include found in a C file
+/// line is the (1-based) physical line of the directive
+/// conditional is set when the include is only live for some values of unknown macros
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Include {
+    pub kind: IncludeKind,
+    pub path: String,
+    pub line: usize,
+    pub conditional: bool,
+}
+
+/// A preprocessor directive, after line splicing and comment removal
+/// Example: "  #  include \"a.h\" // comment" gives
+/// name: "include", body: "\"a.h\""
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directive {
+    pub name: String,
+    pub body: String,
+    pub line: usize,
+}
+
+impl Directive {
+    /// Tries to interpret the directive as an #include
+    /// Returns None if it is not an include or if the included path is computed
+    /// by a macro, which we cannot follow
+    pub fn as_include(&self) -> Option<Include> {
+        if self.name != "include" {
+            return None;
+        }
+        let mut chars = self.body.chars();
+        let (kind, close) = match chars.next() {
+            Some('"') => (IncludeKind::Quoted, '"'),
+            Some('<') => (IncludeKind::Angled, '>'),
+            _ => return None,
+        };
+        let path: String = chars.by_ref().take_while(|c| *c != close).collect();
+        if path.is_empty() || !self.body[1 + path.len()..].starts_with(close) {
+            // Unterminated include, nothing sensible to follow
+            return None;
+        }
+        Some(Include {
+            kind,
+            path,
+            line: self.line,
+            conditional: false,
+        })
+    }
+}
+
+/// Where the lexer currently is inside the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    LineComment,
+    BlockComment,
+    StringLit,
+    CharLit,
+}
+
+/// Splits C source into logical lines, each with the physical line it starts at.
+/// Handles backslash line splices, /* */ and // comments (which are replaced by a space,
+/// like the preprocessor does), and string or character literals, which are kept as they are
+pub fn logical_lines(source: &str) -> Vec<(String, usize)> {
+    let mut lines = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut state = State::Code;
+
+    // Current logical line and the physical line it started at
+    let mut logical = String::new();
+    let mut line = 1;
+    let mut logical_start = 1;
+
+    while let Some(c) = chars.next() {
+        // Line splices are removed before anything else looks at the source
+        if c == '\\' {
+            match chars.peek() {
+                Some('\n') => {
+                    chars.next();
+                    line += 1;
+                    continue;
+                }
+                Some('\r') => {
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    line += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        if c == '\n' {
+            line += 1;
+            if state == State::BlockComment {
+                // The comment (and the logical line) goes on
+                continue;
+            }
+            // Unterminated literals and line comments both end here
+            state = State::Code;
+            lines.push((std::mem::take(&mut logical), logical_start));
+            logical_start = line;
+            continue;
+        }
+
+        match state {
+            State::Code => match c {
+                '/' if chars.peek() == Some(&'/') => {
+                    chars.next();
+                    state = State::LineComment;
+                    logical.push(' ');
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    state = State::BlockComment;
+                    logical.push(' ');
+                }
+                '"' => {
+                    state = State::StringLit;
+                    logical.push(c);
+                }
+                '\'' => {
+                    state = State::CharLit;
+                    logical.push(c);
+                }
+                _ => logical.push(c),
+            },
+            State::LineComment => {}
+            State::BlockComment => {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    state = State::Code;
+                }
+            }
+            State::StringLit | State::CharLit => {
+                logical.push(c);
+                let close = if state == State::StringLit { '"' } else { '\'' };
+                if c == '\\' {
+                    // Escaped character, cannot close the literal
+                    if let Some(&escaped) = chars.peek() {
+                        if escaped != '\n' {
+                            chars.next();
+                            logical.push(escaped);
+                        }
+                    }
+                } else if c == close {
+                    state = State::Code;
+                }
+            }
+        }
+    }
+
+    if !logical.is_empty() {
+        lines.push((logical, logical_start));
+    }
+    lines
+}
+
+/// Returns the directive on the given logical line, if there is one
+/// Whitespace is allowed before and after the '#'
+pub fn directive_of(logical: &str, line: usize) -> Option<Directive> {
+    let rest = logical.trim_start().strip_prefix('#')?.trim_start();
+    let name_len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    if name_len == 0 {
+        // The null directive "#" on its own does nothing
+        return None;
+    }
+    let (name, body) = rest.split_at(name_len);
+    Some(Directive {
+        name: name.to_string(),
+        body: body.trim().to_string(),
+        line,
+    })
+}
+
+/// Every directive of the C source, in order
+pub fn directives(source: &str) -> Vec<Directive> {
+    logical_lines(source)
+        .iter()
+        .filter_map(|(logical, line)| directive_of(logical, *line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn includes(source: &str) -> Vec<Include> {
+        logical_lines(source)
+            .iter()
+            .filter_map(|(logical, line)| directive_of(logical, *line)?.as_include())
+            .collect()
+    }
+
+    /// The paths of the includes of the source with their lines
+    fn paths(source: &str) -> Vec<(String, usize)> {
+        includes(source).into_iter().map(|include| (include.path, include.line)).collect()
+    }
+
+    #[test]
+    fn finds_includes_however_they_are_spaced() {
+        let source = "#include \"a.h\"\n# include \"b.h\"\n\t#include <c.h>\n  #\tinclude\t\"d.h\"\n";
+        assert_eq!(
+            paths(source),
+            vec![("a.h".to_string(), 1), ("b.h".to_string(), 2), ("c.h".to_string(), 3), ("d.h".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn tells_quoted_from_angled_includes() {
+        let kinds: Vec<IncludeKind> = includes("#include \"a.h\"\n#include <a.h>\n").iter().map(|include| include.kind).collect();
+        assert_eq!(kinds, vec![IncludeKind::Quoted, IncludeKind::Angled]);
+    }
+
+    #[test]
+    fn drops_trailing_comments() {
+        let source = "#include \"a.h\" // the a\n#include \"b.h\" /* the b */\n";
+        assert_eq!(paths(source), vec![("a.h".to_string(), 1), ("b.h".to_string(), 2)]);
+    }
+
+    #[test]
+    fn splices_lines_and_keeps_the_first_line() {
+        let source = "int x;\n#include \\\n\"a.h\"\n#include \"b.h\"\n";
+        assert_eq!(paths(source), vec![("a.h".to_string(), 2), ("b.h".to_string(), 4)]);
+    }
+
+    #[test]
+    fn ignores_includes_in_comments() {
+        let source = "// #include \"a.h\"\n/*\n#include \"b.h\"\n*/\n#include \"c.h\"\n";
+        assert_eq!(paths(source), vec![("c.h".to_string(), 5)]);
+    }
+
+    #[test]
+    fn ignores_includes_in_string_literals() {
+        let source = "const char *s = \"\\\n#include \\\"a.h\\\"\";\nchar c = '#';\n#include \"b.h\"\n";
+        assert_eq!(paths(source), vec![("b.h".to_string(), 4)]);
+    }
+
+    #[test]
+    fn cannot_follow_computed_or_unterminated_includes() {
+        assert_eq!(paths("#include HEADER\n#include \"a.h\n#include <b.h\n"), vec![]);
+    }
+
+    #[test]
+    fn the_null_directive_is_no_directive() {
+        assert!(logical_lines("#\n  #  \n").iter().all(|(logical, line)| directive_of(logical, *line).is_none()));
+    }
+}
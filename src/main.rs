@@ -1,93 +1,303 @@
 use std::io::prelude::*;
+use std::path::Path;
 use std::{
     env,
     fs::{self, File},
+    process,
+    sync::Arc,
 };
 use termion::*;
 
+#[macro_use]
+mod utils;
+use utils::diagnostics::{self, DiagnosticsFormat};
+use utils::errors::*;
+use utils::utilities::{set_output, verbosity, Makefile, Verbosity, BUILD_DIR};
+
+mod lexer;
+mod preprocessor;
+
+mod symbols;
+
+mod config;
+use config::*;
+
+mod cache;
+use cache::{ScanCache, CACHE_DIR};
+
 mod maker;
 use maker::*;
 
-mod utils;
-use utils::errors::*;
-use utils::utilities::*;
+mod backends;
+
+mod graph;
+
+mod cli;
+use cli::{Cli, ColorChoice, Command};
 
 /// Runs the Makefile maker (pun intended)
 /// The binary of nofile must be put in the same FOLDER!
 /// as the starting point of the program
-/// Usage: ./nofile <start1.c> <start2.c> ...
+/// Usage: ./nofile [COMMAND] [OPTIONS] <start1.c> <start2.c> ..., see cli::USAGE
 fn main() {
     let args: Vec<String> = env::args().collect();
-    match parse_args(args) {
-        Ok(entrypoints) => {
-            println!("Valid files. Proceeding\n");
-            let makefile = run(entrypoints);
-            let formatted = makefile.format();
-            println!(
-                "{}Makefile construction succeeded. Outputing\n",
-                color::Fg(color::Green)
-            );
-            println!("{}\n", formatted);
-            let file = File::create("_Makefile");
-            match file {
-                Ok(mut file) => {
-                    let written = file.write_all(formatted.as_bytes());
-                    match written {
-                        Ok(_) => println!("{}--- SUCCESS ---", color::Fg(color::Green)),
-                        Err(e) => {
-                            println!(
-                                "{}File writing failed with error:\n {}",
-                                color::Fg(color::Red),
-                                e
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!(
-                        "{}File creation failed, aborting. Error: \n {}",
-                        color::Fg(color::Red),
-                        e
-                    );
-                }
+    // Until the command line is read, print like --color=auto does, and report the diagnostics
+    // the way it asks already, for the errors of the configuration file and of the command line
+    let (format, output) = cli::diagnostics_args(&args);
+    report_diagnostics(format, output.as_deref());
+    set_printing(format, output.as_deref(), Verbosity::Normal, ColorChoice::Auto.enabled());
+    // The command line overrides what the configuration file says
+    let config = Config::load(CONFIG_FILE);
+    let options = config.as_ref().map(ScanOptions::from_config).unwrap_or_default();
+    let parsed = cli::parse(args, options);
+    // Even with a bad configuration file
+    if parsed.as_ref().is_ok_and(|cli| cli.help) {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let cli = match config.and(parsed) {
+        Ok(cli) => cli,
+        Err(nf_err) => exit(nf_err),
+    };
+    // An @FILE may have asked for other diagnostics
+    if (cli.diagnostics, cli.diagnostics_output.as_deref()) != (format, output.as_deref()) {
+        report_diagnostics(cli.diagnostics, cli.diagnostics_output.as_deref());
+    }
+    set_printing(cli.diagnostics, cli.diagnostics_output.as_deref(), cli.verbosity, cli.color.enabled());
+    let done = match cli.command {
+        Command::Generate => generate(cli),
+        Command::Graph => graph(cli),
+        Command::Check => check(cli),
+        Command::Explain => explain(cli),
+        Command::Init => init(cli),
+        Command::Clean => clean(cli),
+    };
+    if let Err(nf_err) = done {
+        exit(nf_err);
+    }
+    diagnostics::finish();
+}
+
+/// Reports the diagnostics in the given format, to the file or the standard error
+fn report_diagnostics(format: DiagnosticsFormat, output: Option<&str>) {
+    if let Err(nf_err) = diagnostics::set_sink(format, output) {
+        exit(nf_err);
+    }
+}
+
+/// Prints as verbose as asked, but nothing besides the diagnostics when they go to
+/// the standard error in a format which is not for people
+fn set_printing(format: DiagnosticsFormat, output: Option<&str>, verbosity: Verbosity, color: bool) {
+    let verbosity = match (format, output) {
+        (DiagnosticsFormat::Human, _) | (_, Some(_)) => verbosity,
+        _ => Verbosity::Quiet,
+    };
+    set_output(verbosity, color);
+}
+
+/// Reports the error the way asked and exits
+fn exit(nf_err: NFError) -> ! {
+    nf_err.diagnostic();
+    diagnostics::finish();
+    process::exit(nf_err.exit_code());
+}
+
+/// Prints a message about how things are going, unless asked to be quiet
+macro_rules! status {
+    ($($args:tt)*) => {
+        if verbosity() >= Verbosity::Normal {
+            print_green![$($args)*];
+        }
+    }
+}
+
+/// Prints more about what was found and done, when asked to be verbose
+macro_rules! detail {
+    ($($args:tt)*) => {
+        if verbosity() >= Verbosity::Verbose {
+            print_white![$($args)*];
+        }
+    }
+}
+
+/// Scans the executables given on the command line, or the ones of the configuration,
+/// and prints the warnings. Fails with every problem found when one of them is an error.
+/// The scanning options are used up
+fn scan(cli: &mut Cli) -> Result<Makefile, NFError> {
+    let entrypoints = cli::entrypoints(&cli.files, cli.discover.as_deref(), &cli.options)?;
+    detail!["Scanning {} executables", entrypoints.len()];
+    let strict = cli.options.strict;
+    let store = cli.cache.then(|| Arc::new(ScanCache::load(cli.options.fingerprint())));
+    cli.options.store = store.clone();
+    let mut makefile = run(entrypoints, std::mem::take(&mut cli.options));
+    // The scan is over, so nothing else holds the cache
+    if let Some(store) = store.and_then(|store| Arc::try_unwrap(store).ok()) {
+        let (parsed, reused) = store.stats();
+        detail!["Parsed {} files, {} were unchanged since the last run", parsed, reused];
+        // check and --dry-run write nothing
+        if !cli.dry_run && cli.command != Command::Check {
+            if let Err(e) = store.save() {
+                let message = format!("the scan cache could not be saved: {}", e);
+                diagnostics::report(&Diagnostic::warning("cache-not-saved", message).at(CACHE_DIR, None));
             }
         }
-        Err(nf_err) => {
-            nf_err.diagnostic();
+    }
+    // The problems of the scan, then the ones found in what it gives, failing once with all of them
+    let mut problems: Vec<Diagnostic> = makefile.diagnostics().to_vec();
+    makefile.unresolved().into_iter().for_each(|(source, symbols)| {
+        let message = format!("uses symbols which are declared but never defined: {}", symbols.join(", "));
+        problems.push(Diagnostic::warning("undefined-symbols", message).at(&source, None));
+    });
+    let cycles = include_cycles(makefile.includes());
+    let cut = cycles.len() >= MAX_CYCLES;
+    cycles.into_iter().for_each(|cycle| {
+        let files: Vec<&str> = cycle.iter().map(|include| include.including.as_str()).collect();
+        let message = format!("include cycle: {} -> {}", files.join(" -> "), files[0]);
+        // --strict does not allow them
+        let severity = if strict { Severity::Error } else { Severity::Warning };
+        let mut problem = Diagnostic::new(severity, "include-cycle", message);
+        problem = problem.at(&cycle[0].including, Some(cycle[0].line));
+        for include in cycle.iter() {
+            problem = problem.note(format!("{}:{} includes {}", include.including, include.line, include.included));
+        }
+        problems.push(problem);
+    });
+    if cut {
+        if let Some(last) = problems.pop() {
+            problems.push(last.note(format!("only the first {} include cycles are reported", MAX_CYCLES)));
         }
     }
+    if problems.iter().any(Diagnostic::is_error) {
+        return Err(NFError::ScanFailed(problems));
+    }
+    problems.iter().for_each(diagnostics::report);
+    makefile.set_diagnostics(problems);
+    Ok(makefile)
 }
 
-/// Parses the arguments and returns a vector of the filenames and contents
-/// or a NFError if failed for some reason
-fn parse_args(args: Vec<String>) -> Result<Vec<(String, String)>, NFError> {
-    // println!("{:?}", env::current_dir());
-    if args.len() < 2 {
-        return Err(NFError::NotEnoughArgs);
+/// Writes contents to the output of the command: the file, the standard output with --stdout,
+/// or nowhere with --dry-run
+fn write_output(cli: &Cli, path: &str, contents: &str) -> Result<(), NFError> {
+    if cli.stdout {
+        print!("{}", contents);
+        return Ok(());
     }
-    let mut arg_iter = args.into_iter();
-    let mut entrypoints = Vec::new();
+    if cli.dry_run {
+        status!["Would write {} ({} bytes)", path, contents.len()];
+        return Ok(());
+    }
+    let mut file = File::create(path).map_err(|e| NFError::IOError(e, path.to_string()))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| NFError::IOError(e, path.to_string()))?;
+    status!["--- SUCCESS --- Wrote {}", path];
+    Ok(())
+}
 
-    // Skip first argument, which is just the executable name
-    arg_iter.next();
+fn generate(mut cli: Cli) -> Result<(), NFError> {
+    let makefile = scan(&mut cli)?;
+    for (backend, output) in cli.outputs() {
+        let generated = backend.generator().generate(&makefile, &output);
+        detail!["{:?} generation succeeded", backend];
+        write_output(&cli, &output, &generated)?;
+    }
+    Ok(())
+}
 
-    for arg in arg_iter {
-        let path_to_start = arg;
-        if !path_to_start.ends_with(".c") {
-            // not a C file
-            return Err(NFError::InvalidFileExt(path_to_start));
+/// Prints every executable with the C files linked into it or, in the other formats,
+/// the include graph of the project as well (written to --output when given)
+fn graph(mut cli: Cli) -> Result<(), NFError> {
+    let makefile = scan(&mut cli)?;
+    let rendered = graph::render(cli.graph_format, makefile.includes(), &makefile);
+    match cli.output.clone() {
+        Some(output) => write_output(&cli, &output, &rendered),
+        None => {
+            print!("{}", rendered);
+            Ok(())
         }
-        let startpoint = fs::read_to_string(&path_to_start);
-        let contents;
-        match startpoint {
-            Ok(content) => {
-                contents = content;
-                entrypoints.push((path_to_start, contents));
-            }
-            Err(e) => {
-                return Err(NFError::IOError(e, path_to_start));
-            }
-        };
     }
-    Ok(entrypoints)
+}
+
+/// Scans everything and exits with an error when there is something to fix: any warning of the scan
+fn check(mut cli: Cli) -> Result<(), NFError> {
+    let makefile = scan(&mut cli)?;
+    let problems = makefile.diagnostics().len();
+    if problems > 0 {
+        return Err(NFError::CheckFailed(problems));
+    }
+    status!["No problems found in {} executables", makefile.link_sets().len()];
+    Ok(())
+}
+
+/// Prints the chain of includes linking every C file (or only the ones asked for) into the executable
+fn explain(cli: Cli) -> Result<(), NFError> {
+    let (entrypoint, asked) = match cli.files.split_first() {
+        Some(files) => files,
+        None => return Err(NFError::NotEnoughArgs),
+    };
+    cli::entrypoints(std::slice::from_ref(entrypoint), None, &cli.options)?;
+    if cli.options.link_mode == LinkMode::Symbols {
+        let message = "explain follows the includes, the symbols are not looked at".to_string();
+        diagnostics::report(&Diagnostic::warning("explain-symbols", message));
+    }
+    let asked: Vec<String> = asked.iter().map(|file| normalize(file)).collect();
+    let explanations = maker::explain(entrypoint, &cli.options);
+    explanations
+        .iter()
+        .filter(|explanation| asked.is_empty() || asked.contains(&explanation.source))
+        .for_each(|explanation| {
+            let conditional = if explanation.conditional { " (only through #if branches on unknown macros)" } else { "" };
+            println!("{} is linked into {}{}", explanation.source, entrypoint, conditional);
+            explanation.chain.iter().for_each(|(including, line, included)| {
+                println!("    {}:{} includes {}", including, line, included);
+            });
+        });
+    asked
+        .iter()
+        .filter(|file| !explanations.iter().any(|explanation| &explanation.source == *file))
+        .for_each(|file| {
+            print_yellow!["{} is not linked into {}", file, entrypoint];
+        });
+    Ok(())
+}
+
+/// Writes a configuration file with the executables given
+fn init(cli: Cli) -> Result<(), NFError> {
+    if !cli.stdout && Path::new(CONFIG_FILE).exists() {
+        return Err(NFError::FileExists(CONFIG_FILE.to_string()));
+    }
+    let options = &cli.options;
+    let quoted = |flags: &[String]| {
+        flags.iter().map(|flag| format!("{:?}", flag)).collect::<Vec<String>>().join(", ")
+    };
+    let mut config = format!(
+        "[build]\ncc = {:?}\ncflags = [{}]\nldflags = []\nldlibs = []\ninclude = [{}]\n",
+        options.compiler,
+        quoted(&options.cflags),
+        quoted(&options.include_dirs),
+    );
+    cli.files.iter().for_each(|file| {
+        config.push_str(format!("\n[[executables]]\nsource = {:?}\n", file).as_str());
+    });
+    write_output(&cli, CONFIG_FILE, &config)
+}
+
+/// Removes the generated files and everything they built
+fn clean(cli: Cli) -> Result<(), NFError> {
+    let mut paths: Vec<String> = cli.outputs().into_iter().map(|(_, output)| output).collect();
+    paths.push(BUILD_DIR.to_string());
+    paths.push(CACHE_DIR.to_string());
+    for path in paths.iter() {
+        let path = Path::new(path);
+        if !path.exists() {
+            continue;
+        }
+        if cli.dry_run {
+            status!["Would remove {}", path.display()];
+            continue;
+        }
+        let removed = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        removed.map_err(|e| NFError::IOError(e, path.display().to_string()))?;
+        status!["Removed {}", path.display()];
+    }
+    Ok(())
 }
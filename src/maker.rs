@@ -1,13 +1,21 @@
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::fs;
-use std::collections::HashSet;
+use std::io;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::path::{Path as StdPath, PathBuf};
 use chashmap::CHashMap;
+use serde::Deserialize;
 
-#[macro_use]
 use crate::utils::utilities::*;
-
 use crate::utils::errors::*;
+use crate::utils::diagnostics;
+use crate::utils::pool::{self, WorkQueue};
+use crate::lexer::{self, Directive, Include, IncludeKind};
+use crate::preprocessor::{self, Defines, UnknownMacros};
+use crate::config::{Config, ExecutableConfig, HeadersConfig, LibraryConfig, MirrorRule, CONFIG_FILE};
+use crate::symbols::{self, SymbolIndex};
+use crate::cache::ScanCache;
 
 /// Path of a file
 /// (Dir name, file name)
@@ -51,43 +59,14 @@ impl FilePath {
         Path(path, file)
     }
 
-    fn file_to_header(&mut self) {
-        match self {
-            Path(_, ref mut file) => replace_with_h(file)
-        };
-    }
-
-    fn file_to_c(&mut self) {
-        match self {
-            Path(_, ref mut file) => replace_with_c(file)
-        };
-
-    }
-
-    /// Ref to the directory string
-    #[inline]
-    fn dir_ref(&self) -> &String {
-        match self { 
-            Path(dir, _) => dir
-        }
-    }
-
     /// Ref to the file string
     #[inline]
     pub fn file_ref(&self) -> &String {
-        match self { 
+        match self {
             Path(_, file) => file
         }
     }
 
-    /// Clone the file string
-    #[inline]
-    fn file_clone(&self) -> String {
-        match self { 
-            Path(_, file) => file.clone()
-        }
-    }
-
     /// Clone the directory string
     #[inline]
     fn dir_clone(&self) -> String {
@@ -108,18 +87,6 @@ impl FilePath {
         }
     }
 
-    /// Combine the directory and file into a whole valid path (String)
-    /// (consumes self)
-    #[inline]
-    fn into_combined(self) -> String {
-        match self { 
-            Path(mut dir, file) => { 
-                dir.push_str(file.as_str());
-                dir
-            }
-        }
-    }
-
     /// Consumes self and splits the path
     /// into (dir, file)
     /// for internal use
@@ -139,193 +106,1793 @@ impl FilePath {
         Path(dir_c, file_new)
     }
 
+    /// The path of the given (relative) file inside the directory dir
+    fn in_dir(dir: &str, file: &str) -> FilePath {
+        let mut path = dir.to_string();
+        if !path.is_empty() && !path.ends_with('/') {
+            path.push('/');
+        }
+        path.push_str(file);
+        FilePath::new(path)
+    }
+
+    #[inline]
+    fn exists(&self) -> bool {
+        StdPath::new(&self.combined()).is_file()
+    }
+
 }
 
-/// Makes a makefile adding dependencies from all the given files 
-/// (vector of pairs of (path, contents)
-pub fn run(entrypoints: Vec<(String, String)>) -> Makefile {
-    let mut source_files = Vec::new();
-    for (k, _) in &entrypoints {
-        source_files.push(StrPath::new(k.clone()));
-    }
-
-    let dependencies: CHashMap<StrPath, HashSet<StrPath>> = CHashMap::new();
-    let arc_dependencies = Arc::new(dependencies);
-    let mut insert_threads = Vec::new();
-
-    // Add all the files in a multi-threaded fashion to the map
-    // This might not matter if the project is small or if you have few files
-    // but if you're dealing with a huge project it will boost performance
-    source_files.iter().for_each(|file| { 
-        let deps = Arc::clone(&arc_dependencies); 
-        let file_c = StrPath::clone(&file);
-        insert_threads.push(thread::spawn(move || {
-            deps.insert(file_c, HashSet::new());
-        }));
-    });
-    insert_threads.into_iter().for_each(|t| { let _ = t.join(); });
-
-    // Since we cloned to add to the hashmap then joined on all of the threads, there are no left
-    // arcs besides this one, so it's safe to unwrap
-    let deps = Arc::try_unwrap(arc_dependencies).expect("I was asked to unwrap an Arc with a strong count bigger than 1. This is a bug! Contact the maintainer");
-    let makefile = Makefile::new("gcc", HashSet::new(), source_files, deps);
-    let arc_file = Arc::new(makefile);
-
-    let mut run_threads = Vec::new();
-    entrypoints.into_iter().for_each(|(file, contents)| { 
-            let arc_file_c = Arc::clone(&arc_file);
-            run_threads.push(thread::spawn(move || {
-                run_one_file(&file, contents, arc_file_c);
-        }));
-    });
-    
-    run_threads.into_iter().for_each(|t| { let _ = t.join(); });
+/// Where an included header was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchDir {
+    /// Next to the file which included it
+    Local,
+    /// In an -iquote directory
+    Quote(String),
+    /// In an -I directory
+    Include(String),
+}
+
+impl SearchDir {
+    /// The flag which adds the search directory, None for the local directory
+    pub fn flag(&self) -> Option<String> {
+        match self {
+            SearchDir::Local => None,
+            SearchDir::Quote(dir) => Some(format!("-iquote {}", dir)),
+            SearchDir::Include(dir) => Some(format!("-I{}", dir)),
+        }
+    }
+}
+
+/// Lexically normalises a path: drops "." components and duplicate slashes
+/// and resolves ".." against the component before it, when there is one
+/// Example: "src/../include//./foo.h" -> "include/foo.h"
+pub fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|last| *last != "..") => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    let normalized = components.join("/");
+    if path.starts_with('/') {
+        format!("/{}", normalized)
+    } else {
+        normalized
+    }
+}
 
-    Arc::try_unwrap(arc_file).expect("Tried to unwrap an arc with a count bigger than 1. This is a bug, please contact maintainer")
+/// All the files under root with the given extension, as normalised paths.
+/// Hidden directories are skipped
+pub fn project_files(root: &str, extension: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_string()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = normalize(&format!("{}/{}", dir, name));
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => dirs.push(path),
+                Ok(_) if name.ends_with(extension) => files.push(path),
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    files
 }
 
-fn run_one_file(start: &String, contents: String, makefile: Arc<Makefile>) {
-    // Get the headers from the file
-    let headers: Vec<&str> = contents
-        .lines()
-        .filter(|line| line.starts_with("#include") && !line.contains("std") && !line.contains("<")).collect();
-    // headers.iter().for_each(|header| println!("{}", header));
+/// Whether the path matches the glob pattern: "*" matches any part of a file or
+/// directory name, "?" any one character and "**" any number of directories
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+    let path: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    match_parts(&pattern, &path)
+}
 
-    let no_include_headers: Vec<String> = headers.iter().map(|header| remove_include(header.to_string())).collect();
-    let start_header = FilePath::new(start.clone());
+fn match_parts(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skipped| match_parts(rest, &path[skipped..])),
+        Some((part, rest)) => match path.split_first() {
+            Some((name, path)) => match_name(part.as_bytes(), name.as_bytes()) && match_parts(rest, path),
+            None => false,
+        },
+    }
+}
 
-    // let mut makefile = Makefile::new("gcc", HashSet::new(), start_header.combined(), HashSet::new());
+fn match_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skipped| match_name(rest, &name[skipped..])),
+        Some((b'?', rest)) => !name.is_empty() && match_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_name(rest, &name[1..]),
+    }
+}
 
+/// Which C files are looked at when discovering the entrypoints.
+/// Both lists hold directories or globs, like "tools" or "**/*_test.c"
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    /// When not empty, only the files under (or matching) one of these are looked at
+    pub include: Vec<String>,
+    /// Files under (or matching) one of these are never looked at
+    pub exclude: Vec<String>,
+}
 
-    for header in no_include_headers {
-        // traverse down the tree and add the extra implications for each header
-        // then finally add each header to the dependency tree
-        let header = FilePath::new(header);
-        let header = FilePath::compose_ref(&start_header, header);
+/// Whether the path is the file or under the directory given by the pattern, or matches it as a glob
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let pattern = normalize(pattern);
+    glob_match(&pattern, path) || glob_match(&format!("{}/**", pattern), path)
+}
 
-        let is_h_file = header.file_ref().ends_with(".h");
+impl Discovery {
+    fn allows(&self, path: &str) -> bool {
+        let matches = |pattern: &String| path_matches(pattern, path);
+        (self.include.is_empty() || self.include.iter().any(matches)) && !self.exclude.iter().any(matches)
+    }
+}
 
-        let mut header_c = header.clone();
-        header_c.file_to_c();
-        
-        // Traverse tree, trying the .c version first, so we can add it first
-        traverse(start.clone(), &header_c, Arc::clone(&makefile));
-        if is_h_file {
-            traverse(start.clone(), &header, Arc::clone(&makefile));
+/// Finds the C files under root which define main, the entrypoints of the executables
+pub fn discover(root: &str, discovery: &Discovery, defines: &Defines, unknown: UnknownMacros) -> Vec<String> {
+    project_files(root, ".c")
+        .into_iter()
+        .filter(|path| discovery.allows(path))
+        .filter(|path| {
+            let contents = fs::read_to_string(path).unwrap_or_default();
+            symbols::defines_main(&preprocessor::preprocess(&contents, false, defines, unknown).code)
+        })
+        .collect()
+}
+
+/// Finds the C files which implement a header
+pub trait ImplementationResolver: Debug + Send + Sync {
+    /// The C files implementing the given (normalised) header path,
+    /// None if this resolver does not know where the header is implemented
+    fn implementations(&self, header: &str) -> Option<Vec<String>>;
+}
+
+/// The C file next to the header, with the same name: foo/bar.h -> foo/bar.c
+#[derive(Debug)]
+pub struct SiblingResolver;
+
+impl ImplementationResolver for SiblingResolver {
+    fn implementations(&self, header: &str) -> Option<Vec<String>> {
+        let source = c_file_of(header)?;
+        if StdPath::new(&source).is_file() {
+            Some(vec![source])
+        } else {
+            None
         }
-        else {
-            let mut full_path = header.combined();
-            replace_with_c(&mut full_path);
-            makefile.add_dependency(&start, full_path);
+    }
+}
+
+/// Explicit implementations of headers, given in the configuration file
+#[derive(Debug)]
+pub struct OverrideResolver(HashMap<String, Vec<String>>);
+
+impl OverrideResolver {
+    pub fn new(overrides: &HashMap<String, Vec<String>>) -> Self {
+        OverrideResolver(
+            overrides
+                .iter()
+                .map(|(header, sources)| {
+                    (normalize(header), sources.iter().map(|source| normalize(source)).collect())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl ImplementationResolver for OverrideResolver {
+    fn implementations(&self, header: &str) -> Option<Vec<String>> {
+        self.0.get(header).cloned()
+    }
+}
+
+/// Directory mirroring rules, like "include/**" -> "src/**",
+/// which maps include/foo/bar.h to src/foo/bar.c
+#[derive(Debug)]
+pub struct MirrorResolver(Vec<MirrorRule>);
+
+impl MirrorResolver {
+    pub fn new(rules: Vec<MirrorRule>) -> Self {
+        MirrorResolver(rules)
+    }
+
+    /// The directory before the "**" of a rule, normalised
+    fn prefix(pattern: &str) -> String {
+        let prefix = pattern.trim_end_matches("**");
+        let prefix = normalize(prefix);
+        if prefix.is_empty() { prefix } else { format!("{}/", prefix) }
+    }
+}
+
+impl ImplementationResolver for MirrorResolver {
+    fn implementations(&self, header: &str) -> Option<Vec<String>> {
+        self.0.iter().find_map(|rule| {
+            let rest = header.strip_prefix(&MirrorResolver::prefix(&rule.from))?;
+            let source = c_file_of(&format!("{}{}", MirrorResolver::prefix(&rule.to), rest))?;
+            if StdPath::new(&source).is_file() {
+                Some(vec![source])
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Looks for a C file with the same name as the header anywhere in the project
+/// When several have that name, the one whose directories look the most like
+/// the header's wins, so include/net/util.h goes with src/net/util.c rather than src/fs/util.c.
+/// A C file defining main is the entrypoint of another executable (or of a test), never an implementation
+#[derive(Debug)]
+pub struct BasenameResolver {
+    root: String,
+    /// C files by their name without the ".c", only looked for once a header needs them
+    index: OnceLock<HashMap<String, Vec<String>>>,
+}
+
+impl BasenameResolver {
+    /// Looks for the C files under root
+    pub fn new(root: &str) -> Self {
+        BasenameResolver {
+            root: root.to_string(),
+            index: OnceLock::new(),
         }
-    } 
+    }
 
+    /// Indexes all the C files under root the first time
+    fn index(&self) -> &HashMap<String, Vec<String>> {
+        self.index.get_or_init(|| {
+            let mut index: HashMap<String, Vec<String>> = HashMap::new();
+            project_files(&self.root, ".c").into_iter().for_each(|path| {
+                let name = path.rsplit('/').next().unwrap_or(&path);
+                let stem = name.trim_end_matches(".c").to_string();
+                index.entry(stem).or_default().push(path);
+            });
+            index
+        })
+    }
+
+    /// Whether the C file defines main in any #if branch, so it is never taken for another one
+    fn defines_main(source: &str) -> bool {
+        let contents = fs::read_to_string(source).unwrap_or_default();
+        let code = preprocessor::preprocess(&contents, false, &Defines::new(), UnknownMacros::Both).code;
+        symbols::defines_main(&code)
+    }
+
+    /// How many of the directories right above the file have the same names in both paths
+    fn similarity(header: &str, source: &str) -> usize {
+        header
+            .rsplit('/')
+            .skip(1)
+            .zip(source.rsplit('/').skip(1))
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
 }
 
-/// Replaces the ".h" at the end of the file name
-/// with a ".c" for the linker
-/// PRE: File must end with either ".h" or ".c"
-#[inline]
-fn replace_with_c(c_file: &mut String) {
-    // Sanity check
-    if !(c_file.ends_with(".c") || c_file.ends_with(".h")) {
-        panic!("You gave me a file with no .c or .h extension");
+impl ImplementationResolver for BasenameResolver {
+    fn implementations(&self, header: &str) -> Option<Vec<String>> {
+        let name = header.rsplit('/').next()?;
+        let candidates: Vec<&String> = self
+            .index()
+            .get(name.strip_suffix(".h")?)?
+            .iter()
+            .filter(|source| !BasenameResolver::defines_main(source))
+            .collect();
+        let best = candidates
+            .iter()
+            .map(|source| BasenameResolver::similarity(header, source))
+            .max()?;
+        let mut best_candidates = candidates
+            .iter()
+            .filter(|source| BasenameResolver::similarity(header, source) == best);
+        let source = best_candidates.next()?;
+        if best_candidates.next().is_some() {
+            // Ambiguous, better to link nothing than the wrong file
+            return None;
+        }
+        Some(vec![source.to_string()])
     }
-    c_file.pop();
-    c_file.push('c');
 }
 
+/// Maps headers to the C files implementing them, asking each resolver in turn
+#[derive(Debug)]
+pub struct Implementations(Vec<Box<dyn ImplementationResolver>>);
 
-/// Replaces the ".c" at the end of the file name
-/// with a ".h" for the linker
-/// PRE: File must end with either ".h" or ".c"
-#[inline]
-fn replace_with_h(c_file: &mut String) {
-    // Sanity check
-    if !(c_file.ends_with(".c") || c_file.ends_with(".h")) {
-        panic!("You gave me a file with no .c or .h extension");
-    }
-    c_file.pop();
-    c_file.push('h');
-}
-
-/// Traverses the file tree from given path to header
-fn traverse(start: String, header: &FilePath, makefile: Arc<Makefile>) {
-    // TODO: Change the header from &String to &Path
-    let header_path = header.combined();
-    let header_contents = fs::read_to_string(&header_path);
-    match header_contents {
-        Ok(contents) => {
-
-            // If we succeeded opening the file and it is a .c file, add it to the dependencies
-            if header_path.ends_with(".c") {
-                makefile.add_dependency(&start, header_path);
+impl Default for Implementations {
+    fn default() -> Self {
+        Implementations(vec![Box::new(SiblingResolver)])
+    }
+}
+
+impl Implementations {
+    /// The resolvers of the configuration, in order: the explicit implementations,
+    /// the C file next to the header, the mirroring rules and finally, when asked for,
+    /// a C file with the same name anywhere under root
+    pub fn from_config(config: &HeadersConfig, root: &str) -> Self {
+        let mut resolvers: Vec<Box<dyn ImplementationResolver>> = vec![
+            Box::new(OverrideResolver::new(&config.implementations)),
+            Box::new(SiblingResolver),
+            Box::new(MirrorResolver::new(config.mirror.clone())),
+        ];
+        if config.basename {
+            resolvers.push(Box::new(BasenameResolver::new(root)));
+        }
+        Implementations(resolvers)
+    }
+
+    /// The C files to link for the given included file: itself if it is a C file,
+    /// otherwise the implementations of the header (possibly none)
+    fn find(&self, included: &FilePath) -> Vec<FilePath> {
+        let path = included.combined();
+        if path.ends_with(".c") {
+            return vec![included.clone()];
+        }
+        let header = normalize(&path);
+        self.0
+            .iter()
+            .find_map(|resolver| resolver.implementations(&header))
+            .unwrap_or_default()
+            .into_iter()
+            .map(FilePath::new)
+            .collect()
+    }
+}
+
+/// How the C files to link into an executable are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// The implementations of the headers the executable includes
+    Headers,
+    /// The files defining the symbols the executable uses
+    Symbols,
+}
+
+impl LinkMode {
+    /// Parses the value of --link
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "headers" => Some(LinkMode::Headers),
+            "symbols" => Some(LinkMode::Symbols),
+            _ => None,
+        }
+    }
+}
+
+/// How the generated Makefile rebuilds objects when the headers they include change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderDeps {
+    /// The headers we found are written as prerequisites of each object
+    Explicit,
+    /// The compiler writes them in .d files (-MMD -MP), which the Makefile includes
+    Depfiles,
+}
+
+impl HeaderDeps {
+    /// Parses the value of --header-deps
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "explicit" => Some(HeaderDeps::Explicit),
+            "depfiles" => Some(HeaderDeps::Depfiles),
+            _ => None,
+        }
+    }
+}
+
+/// Where gcc looks for system headers after the -isystem directories, on most systems
+static SYSTEM_DIRS: [&str; 2] = ["/usr/local/include", "/usr/include"];
+
+/// The CFLAGS used when neither the configuration nor the command line give any
+static DEFAULT_CFLAGS: [&str; 4] = ["-Wall", "-g", "-pedantic", "-std=c99"];
+
+/// The directives of a file, or why it cannot be read
+type ParsedDirectives = Result<Arc<Vec<Directive>>, Arc<io::Error>>;
+
+/// What the preprocessor goes through for a C file, or why the C file cannot be read
+type PreprocessedUnit = Result<Arc<Unit>, Arc<io::Error>>;
+
+/// What the scan learnt about the files, shared by every executable and every worker,
+/// so each file is looked for, read and parsed once per run
+#[derive(Debug, Default)]
+pub struct FileCache {
+    /// Whether there is a file at a path, by normalised path
+    exists: CHashMap<String, bool>,
+    /// The identity of the files (see StrPath), by normalised path
+    identities: CHashMap<String, PathBuf>,
+    /// The directives of every file, by identity. The first worker asking parses the file,
+    /// the others wait for it
+    directives: CHashMap<PathBuf, Arc<OnceLock<ParsedDirectives>>>,
+    /// What the preprocessor goes through for every C file, by identity, worked out once
+    units: CHashMap<PathBuf, Arc<OnceLock<PreprocessedUnit>>>,
+    /// The C files implementing every included file, by identity
+    implementations: CHashMap<PathBuf, Arc<Vec<FilePath>>>,
+}
+
+/// The cell of the key in the map, put there by the first one asking
+fn cell_of<T>(map: &CHashMap<PathBuf, Arc<OnceLock<T>>>, key: PathBuf) -> Arc<OnceLock<T>> {
+    let mut found = None;
+    map.alter(key, |cell| {
+        let cell = cell.unwrap_or_else(|| Arc::new(OnceLock::new()));
+        found = Some(Arc::clone(&cell));
+        Some(cell)
+    });
+    found.unwrap_or_else(|| Arc::new(OnceLock::new()))
+}
+
+impl FileCache {
+    fn is_file(&self, file: &FilePath) -> bool {
+        let path = normalize(&file.combined());
+        if let Some(exists) = self.exists.get(&path) {
+            return *exists;
+        }
+        let exists = file.exists();
+        self.exists.insert(path, exists);
+        exists
+    }
+
+    fn identity(&self, file: &FilePath) -> PathBuf {
+        let path = normalize(&file.combined());
+        if let Some(identity) = self.identities.get(&path) {
+            return identity.clone();
+        }
+        let identity = StrPath::identity_of(&path);
+        self.identities.insert(path, identity.clone());
+        identity
+    }
+
+    fn directives(&self, file: &FilePath) -> Arc<OnceLock<ParsedDirectives>> {
+        cell_of(&self.directives, self.identity(file))
+    }
+
+    fn unit(&self, file: &FilePath) -> Arc<OnceLock<PreprocessedUnit>> {
+        cell_of(&self.units, self.identity(file))
+    }
+}
+
+/// An include of a project file met by the preprocessor going through a C file
+#[derive(Debug)]
+struct UnitInclude {
+    /// The normalised path of the including file
+    including: String,
+    include: Include,
+    header: FilePath,
+    /// The normalised path of the header
+    included: String,
+    via: SearchDir,
+    /// Whether the preprocessor only gets there through #if branches on unknown macros
+    conditional: bool,
+    /// The include which brought the preprocessor into the including file, None for the C file
+    parent: Option<usize>,
+}
+
+/// What the preprocessor goes through for a C file, starting with the macros given by the user:
+/// every include of a project file, in the order it meets them, and the includes it cannot follow
+#[derive(Debug, Default)]
+struct Unit {
+    includes: Vec<UnitInclude>,
+    /// The C files implementing what the C file includes, each once, by identity, with whether
+    /// the preprocessor only gets to them through #if branches on unknown macros
+    links: Vec<(PathBuf, FilePath, bool)>,
+    /// The headers found in the search directories, each once
+    found: Vec<(SearchDir, String)>,
+    /// The "quoted" includes found nowhere, outside of #if branches on unknown macros
+    missing: Vec<Diagnostic>,
+    /// The includes which can never be followed
+    problems: Vec<Diagnostic>,
+}
+
+impl Unit {
+    /// The includes leading from the C file to the file of the include at index
+    fn chain(&self, index: usize) -> Vec<IncludeStep> {
+        let mut chain = Vec::new();
+        let mut next = Some(index);
+        while let Some(index) = next {
+            let include = &self.includes[index];
+            chain.push((include.including.clone(), include.include.line, include.included.clone()));
+            next = include.parent;
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+/// Options which change how the C files are scanned and then built
+#[derive(Debug)]
+pub struct ScanOptions {
+    /// Macros given with -D and -U
+    pub defines: Defines,
+    /// What to do with #if conditions on macros nobody defined
+    pub unknown_macros: UnknownMacros,
+    /// Directories given with -iquote, searched for "quoted" includes only
+    pub quote_dirs: Vec<String>,
+    /// Directories given with -I, searched for every include
+    pub include_dirs: Vec<String>,
+    /// Directories given with -isystem, searched after the -I ones. Headers found there are system headers
+    pub system_dirs: Vec<String>,
+    /// Where headers are implemented
+    pub implementations: Implementations,
+    /// Whether to link by headers or by symbols
+    pub link_mode: LinkMode,
+    /// How header changes are tracked in the generated Makefile
+    pub header_deps: HeaderDeps,
+    /// The -D and -U flags, in order, which are given to the compiler as well
+    pub define_flags: Vec<String>,
+    /// The C compiler the Makefile uses
+    pub compiler: String,
+    pub cflags: Vec<String>,
+    pub ldflags: Vec<String>,
+    pub ldlibs: Vec<String>,
+    /// The executables of the configuration file, with their own flags
+    pub executables: Vec<ExecutableConfig>,
+    /// Where to look for entrypoints with --discover
+    pub discovery: Discovery,
+    /// The libraries to build, in the order they were declared
+    pub libraries: Vec<LibraryConfig>,
+    /// How many workers follow the includes
+    pub jobs: usize,
+    /// What was read of the files, shared by the whole run
+    pub files: FileCache,
+    /// What the previous runs read of the files
+    pub store: Option<Arc<ScanCache>>,
+    /// Whether include cycles and missing "quoted" includes are errors
+    pub strict: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            defines: Defines::new(),
+            unknown_macros: UnknownMacros::False,
+            quote_dirs: Vec::new(),
+            include_dirs: Vec::new(),
+            system_dirs: Vec::new(),
+            implementations: Implementations::default(),
+            link_mode: LinkMode::Headers,
+            header_deps: HeaderDeps::Explicit,
+            define_flags: Vec::new(),
+            compiler: "gcc".to_string(),
+            cflags: DEFAULT_CFLAGS.iter().map(|flag| flag.to_string()).collect(),
+            ldflags: Vec::new(),
+            ldlibs: Vec::new(),
+            executables: Vec::new(),
+            discovery: Discovery::default(),
+            libraries: Vec::new(),
+            jobs: pool::default_jobs(),
+            files: FileCache::default(),
+            store: None,
+            strict: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// The options given by the configuration file
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = ScanOptions::default();
+        let build = &config.build;
+        let mut options = ScanOptions {
+            quote_dirs: build.iquote.clone(),
+            include_dirs: build.include.clone(),
+            system_dirs: build.isystem.clone(),
+            implementations: Implementations::from_config(&config.headers, "."),
+            header_deps: config.headers.deps.unwrap_or(defaults.header_deps),
+            unknown_macros: build.unknown_macros.unwrap_or(defaults.unknown_macros),
+            compiler: build.cc.clone().unwrap_or(defaults.compiler),
+            cflags: build.cflags.clone().unwrap_or(defaults.cflags),
+            ldflags: build.ldflags.clone(),
+            ldlibs: build.ldlibs.clone(),
+            executables: config.executables.clone(),
+            discovery: config.discover.clone(),
+            libraries: config.libraries.clone(),
+            ..ScanOptions::default()
+        };
+        build.defines.iter().for_each(|define| options.define(define));
+        build.undefines.iter().for_each(|name| options.undefine(name));
+        options
+    }
+
+    /// Defines a macro given as "NAME" or "NAME=VALUE", for scanning and for the compiler
+    pub fn define(&mut self, define: &str) {
+        self.defines.define_arg(define);
+        self.define_flags.push(format!("-D{}", define));
+    }
+
+    /// Undefines a macro, for scanning and for the compiler
+    pub fn undefine(&mut self, name: &str) {
+        self.defines.undefine(name);
+        self.define_flags.push(format!("-U{}", name));
+    }
+
+    /// The preprocessor flags of the Makefile: the macros and then the search paths
+    pub fn cpp_flags(&self) -> Vec<String> {
+        let mut flags = self.define_flags.clone();
+        flags.extend(self.include_flags());
+        flags
+    }
+
+    /// What the scan depends on besides the files: the macros and the search paths.
+    /// The scan cache is thrown away when it changes
+    pub fn fingerprint(&self) -> String {
+        format!("{:?} {} {}", self.unknown_macros, self.cpp_flags().join(" "), self.defines.fingerprint())
+    }
+
+    /// Parses the directives of the file, or takes them from the scan cache when it did not change.
+    /// contents gives the file when it was already read
+    fn parse(&self, file: &FilePath, contents: Option<&str>) -> ParsedDirectives {
+        let path = file.combined();
+        let directives = match (&self.store, contents) {
+            (Some(store), _) => store.directives(&normalize(&path), contents, lexer::directives),
+            (None, Some(contents)) => Some(lexer::directives(contents)),
+            (None, None) => None,
+        };
+        match directives {
+            Some(directives) => Ok(Arc::new(directives)),
+            // Not parsed yet, or the cache could not read it either: reading tells why
+            None => fs::read_to_string(&path)
+                .map(|contents| Arc::new(lexer::directives(&contents)))
+                .map_err(Arc::new),
+        }
+    }
+
+    /// The directives of the file, read and parsed the first time only
+    fn directives_of(&self, file: &FilePath) -> ParsedDirectives {
+        self.files
+            .directives(file)
+            .get_or_init(|| self.parse(file, None))
+            .clone()
+    }
+
+    /// The C files to link for the included file, looked for the first time only
+    fn implementations_of(&self, included: &FilePath) -> Arc<Vec<FilePath>> {
+        let identity = self.files.identity(included);
+        if let Some(found) = self.files.implementations.get(&identity) {
+            return Arc::clone(&found);
+        }
+        let found = Arc::new(self.implementations.find(included));
+        self.files.implementations.insert(identity, Arc::clone(&found));
+        found
+    }
+
+    /// Parses a file which was already read, so it is not read again
+    fn remember(&self, file: &FilePath, contents: &str) {
+        self.files
+            .directives(file)
+            .get_or_init(|| self.parse(file, Some(contents)));
+    }
+
+    /// What the preprocessor goes through for the C file, worked out the first time only
+    fn unit_of(&self, file: &FilePath) -> PreprocessedUnit {
+        self.files
+            .unit(file)
+            .get_or_init(|| {
+                let directives = self.directives_of(file)?;
+                let mut unit = Unit::default();
+                let visited = Visited::new();
+                first_visit(&visited, self.files.identity(file), false);
+                let mut defines = self.defines.clone();
+                self.preprocess(file, &directives, None, false, &mut defines, &mut unit, &visited);
+                // Once for every executable linking the C file
+                let mut linked: HashMap<PathBuf, usize> = HashMap::new();
+                for include in unit.includes.iter() {
+                    for implementation in self.implementations_of(&include.header).iter() {
+                        let identity = self.files.identity(implementation);
+                        match linked.get(&identity) {
+                            Some(&index) => unit.links[index].2 &= include.conditional,
+                            None => {
+                                linked.insert(identity.clone(), unit.links.len());
+                                unit.links.push((identity, implementation.clone(), include.conditional));
+                            }
+                        }
+                    }
+                    if include.via != SearchDir::Local {
+                        let found = (include.via.clone(), include.header.combined());
+                        if !unit.found.contains(&found) {
+                            unit.found.push(found);
+                        }
+                    }
+                }
+                Ok(Arc::new(unit))
+            })
+            .clone()
+    }
+
+    /// Goes through the directives of the file with the macros where it is included, following
+    /// its includes into the project files, each one the first time only like include guards do.
+    /// Everything met goes into the unit
+    #[allow(clippy::too_many_arguments)]
+    fn preprocess(
+        &self,
+        file: &FilePath,
+        directives: &[Directive],
+        parent: Option<usize>,
+        conditional: bool,
+        defines: &mut Defines,
+        unit: &mut Unit,
+        visited: &Visited,
+    ) {
+        let path = normalize(&file.combined());
+        let header = file.file_ref().ends_with(".h");
+        preprocessor::walk(directives, header, defines, self.unknown_macros, |include, defines| {
+            let conditional = conditional || include.conditional;
+            let (header, via) = match self.resolve(&include, file) {
+                Some(found) => found,
+                // A "quoted" include found nowhere is most likely a typo, which the compiler would
+                // only tell about much later. The ones in #if branches on unknown macros may never be compiled
+                None if include.kind == IncludeKind::Quoted && !conditional && !self.is_system_header(&include) => {
+                    let message = format!("\"{}\" is included but found nowhere", include.path);
+                    let severity = if self.strict { Severity::Error } else { Severity::Warning };
+                    let problem = Diagnostic::new(severity, "missing-include", message)
+                        .at(&path, Some(include.line))
+                        .note(format!("searched in {}", self.searched_dirs(file).join(", ")));
+                    unit.missing.push(problem);
+                    return;
+                }
+                // System header, nothing to follow
+                None => return,
+            };
+            if !(header.file_ref().ends_with(".c") || header.file_ref().ends_with(".h")) {
+                let problem = Diagnostic::error("not-c-or-h", format!(
+                    "includes {}, which is neither a .c nor a .h file",
+                    normalize(&header.combined())
+                ));
+                unit.problems.push(problem.at(&path, Some(include.line)));
+                return;
             }
-            // Get the next headers
-            let new_headers: Vec<&str> = contents
-                .lines()
-                .filter(|line| line.starts_with("#include") && !line.contains("std") && !line.contains("<")).collect();
-            let no_include_headers: Vec<String> = new_headers
+            let index = unit.includes.len();
+            let followed = first_visit(visited, self.files.identity(&header), conditional);
+            unit.includes.push(UnitInclude {
+                including: path.clone(),
+                include,
+                header: header.clone(),
+                included: normalize(&header.combined()),
+                via,
+                conditional,
+                parent,
+            });
+            if !followed {
+                return;
+            }
+            match self.directives_of(&header) {
+                Ok(directives) => self.preprocess(&header, &directives, Some(index), conditional, defines, unit, visited),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    let problem = Diagnostic::error("unreadable-file", format!("cannot be read: {}", e));
+                    unit.problems.push(problem.at(&normalize(&header.combined()), None));
+                }
+            }
+        });
+    }
+
+    /// The search path flags for the compiler, in the order gcc searches them
+    pub fn include_flags(&self) -> Vec<String> {
+        let quote = self.quote_dirs.iter().map(|dir| SearchDir::Quote(dir.clone()));
+        let include = self.include_dirs.iter().map(|dir| SearchDir::Include(dir.clone()));
+        let mut flags: Vec<String> = quote.chain(include).filter_map(|dir| dir.flag()).collect();
+        self.system_dirs
+            .iter()
+            .for_each(|dir| flags.push(format!("-isystem {}", dir)));
+        flags
+    }
+
+    /// Finds the project header named by the include, searching like gcc does:
+    /// the directory of the including file (for "quoted" includes only), then the -iquote
+    /// directories (also only for "quoted" includes) and then the -I directories.
+    /// Returns None for system headers: gcc searches the -isystem directories and its own
+    /// ones only after all of these, so whatever is not found here is a system header
+    fn resolve(&self, include: &Include, including: &FilePath) -> Option<(FilePath, SearchDir)> {
+        let file = FilePath::new(include.path.clone());
+        let mut candidates = Vec::new();
+        if include.kind == IncludeKind::Quoted {
+            candidates.push((FilePath::compose_ref(including, file), SearchDir::Local));
+            self.quote_dirs.iter().for_each(|dir| {
+                candidates.push((FilePath::in_dir(dir, &include.path), SearchDir::Quote(dir.clone())));
+            });
+        }
+        self.include_dirs.iter().for_each(|dir| {
+            candidates.push((FilePath::in_dir(dir, &include.path), SearchDir::Include(dir.clone())));
+        });
+        candidates.into_iter().find(|(path, _)| self.files.is_file(path))
+    }
+
+    /// Where a "quoted" include of the file is looked for, in order
+    fn searched_dirs(&self, including: &FilePath) -> Vec<String> {
+        let local = normalize(&including.dir_clone());
+        let mut dirs = vec![if local.is_empty() { ".".to_string() } else { local }];
+        self.quote_dirs.iter().chain(self.include_dirs.iter()).for_each(|dir| {
+            let dir = normalize(dir);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        });
+        dirs
+    }
+
+    /// Whether a header which is not in the project is in the -isystem directories or the
+    /// usual system ones, where gcc finds "quoted" system headers as well
+    fn is_system_header(&self, include: &Include) -> bool {
+        self.system_dirs
+            .iter()
+            .map(String::as_str)
+            .chain(SYSTEM_DIRS.iter().copied())
+            .any(|dir| self.files.is_file(&FilePath::in_dir(dir, &include.path)))
+    }
+}
+
+/// Makes a makefile adding dependencies from all the given files 
+/// (vector of pairs of (path, contents).
+/// Everything is scanned even when there are problems, which are all given back together
+/// as the diagnostics of the makefile, the errors too: the caller adds its own and decides
+pub fn run(entrypoints: Vec<(String, String)>, options: ScanOptions) -> Makefile {
+    // The same entrypoint given twice (maybe through different paths) is only built once
+    let mut source_files: Vec<StrPath> = Vec::new();
+    let entrypoints: Vec<(String, String)> = entrypoints
+        .into_iter()
+        .map(|(path, contents)| (normalize(&path), contents))
+        .filter(|(path, _)| {
+            let source = StrPath::new(path.clone());
+            if source_files.contains(&source) {
+                return false;
+            }
+            source_files.push(source);
+            true
+        })
+        .collect();
+
+    let deps: CHashMap<StrPath, HashSet<StrPath>> = CHashMap::new();
+    source_files.iter().for_each(|file| {
+        deps.insert(file.clone(), HashSet::new());
+    });
+    let mut makefile = Makefile::new(
+        options.compiler.clone(),
+        options.cflags.clone(),
+        options.cpp_flags(),
+        source_files,
+        deps,
+    );
+    makefile.link_with(options.ldflags.clone(), options.ldlibs.clone(), options.executables.clone());
+    let mut problems = match options.link_mode {
+        LinkMode::Headers => link_by_includes(entrypoints, &makefile, &options),
+        LinkMode::Symbols => link_by_symbols(&entrypoints, &makefile, &options),
+    };
+    // The workers found them in any order, and every executable including the file finds them again
+    problems.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    problems.dedup_by(|a, b| a.location() == b.location() && a.message == b.message);
+    makefile.set_diagnostics(problems);
+
+    record_includes(&mut makefile, &options);
+    build_libraries(&mut makefile, &options);
+    track_headers(&mut makefile, &options);
+    makefile
+}
+
+/// Gives the libraries of the configuration their C files, and puts them in the order they
+/// are linked: a library goes before the ones it uses
+fn build_libraries(makefile: &mut Makefile, options: &ScanOptions) {
+    if options.libraries.is_empty() {
+        return;
+    }
+    let link_sets = makefile.link_sets();
+    let entrypoints: HashSet<String> = link_sets.iter().map(|(source, _, _)| normalize(source)).collect();
+    let project = project_files(".", ".c");
+    // The C files every executable links
+    let mut common: Option<BTreeSet<String>> = None;
+    link_sets.iter().for_each(|(_, sources, _)| {
+        let sources: BTreeSet<String> = sources.iter().map(|source| normalize(source)).collect();
+        common = Some(match common.take() {
+            Some(common) => common.intersection(&sources).cloned().collect(),
+            None => sources,
+        });
+    });
+
+    // A C file goes into the first library which wants it, and entrypoints go into none
+    let mut taken: HashSet<String> = HashSet::new();
+    let libraries: Vec<Library> = options
+        .libraries
+        .iter()
+        .filter_map(|config| {
+            let sources: Vec<String> = match &config.sources {
+                Some(patterns) => project
+                    .iter()
+                    .filter(|source| patterns.iter().any(|pattern| path_matches(pattern, source)))
+                    .cloned()
+                    .collect(),
+                None => common.iter().flatten().cloned().collect(),
+            };
+            let sources: Vec<String> = sources
+                .into_iter()
+                .filter(|source| !entrypoints.contains(source) && taken.insert(source.clone()))
+                .collect();
+            if sources.is_empty() {
+                let message = format!("the library {} has no C files, it is not built", config.name);
+                diagnostics::report(&Diagnostic::warning("empty-library", message).at(CONFIG_FILE, None));
+                return None;
+            }
+            Some(Library {
+                name: config.name.clone(),
+                kind: config.kind,
+                version: config.version.clone(),
+                sources,
+            })
+        })
+        .collect();
+
+    // Which libraries use which, through the headers their C files include
+    let owner: HashMap<&String, usize> = libraries
+        .iter()
+        .enumerate()
+        .flat_map(|(index, library)| library.sources.iter().map(move |source| (source, index)))
+        .collect();
+    let uses: Vec<HashSet<usize>> = libraries
+        .iter()
+        .enumerate()
+        .map(|(index, library)| {
+            library
+                .sources
                 .iter()
-                .map(|head| remove_include(head.to_string())).collect();
+                .flat_map(|source| headers_of(source, options))
+                .flat_map(|header| options.implementations_of(&FilePath::new(header)).to_vec())
+                .filter_map(|source| owner.get(&normalize(&source.combined())).copied())
+                .filter(|used| *used != index)
+                .collect()
+        })
+        .collect();
 
+    // A library can be placed once all the libraries using it are, cycles are left in the declared order
+    let mut placed: Vec<usize> = Vec::new();
+    while placed.len() < libraries.len() {
+        let ready = (0..libraries.len()).find(|&index| {
+            !placed.contains(&index)
+                && (0..libraries.len()).all(|user| user == index || placed.contains(&user) || !uses[user].contains(&index))
+        });
+        let next = ready.unwrap_or_else(|| (0..libraries.len()).find(|index| !placed.contains(index)).unwrap());
+        placed.push(next);
+    }
+    let mut libraries: Vec<Option<Library>> = libraries.into_iter().map(Some).collect();
+    makefile.set_libraries(placed.into_iter().filter_map(|index| libraries[index].take()).collect());
+}
+
+/// Records in the makefile the include graph reached from the executables
+fn record_includes(makefile: &mut Makefile, options: &ScanOptions) {
+    let entrypoints: Vec<String> = makefile.link_sets().into_iter().map(|(source, _, _)| source).collect();
+    makefile.set_includes(include_graph(&entrypoints, options));
+}
 
-            let mut traversing_threads = Vec::new();
+/// Records in the makefile how the objects depend on headers.
+/// For explicit prerequisites that means finding every header of every translation unit
+fn track_headers(makefile: &mut Makefile, options: &ScanOptions) {
+    let object_headers = match options.header_deps {
+        HeaderDeps::Explicit => makefile
+            .translation_units()
+            .into_iter()
+            .map(|source| {
+                let headers = headers_of(&source, options).into_iter().collect();
+                (source, headers)
+            })
+            .collect(),
+        HeaderDeps::Depfiles => Vec::new(),
+    };
+    makefile.track_headers(options.header_deps, object_headers);
+}
 
-            no_include_headers.into_iter().for_each(|mut head| {
-                if !(head.ends_with(".c") || head.ends_with(".h")) {
-                    // Not a valid header, aborting
-                    let mut dir = header.dir_clone();
-                    let head_c = head.clone();
-                    dir.push_str(head_c.as_str());
-                    let nferr = NFError::InvalidFileExt(dir);
-                    nferr.diagnostic();
+/// All the project headers the given C file includes, directly or not
+fn headers_of(source: &str, options: &ScanOptions) -> BTreeSet<String> {
+    match options.unit_of(&FilePath::new(normalize(source))) {
+        Ok(unit) => unit.includes.iter().map(|include| include.included.clone()).collect(),
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+/// Adds to the makefile the C files implementing the headers each entrypoint includes,
+/// giving back the problems found on the way
+fn link_by_includes(entrypoints: Vec<(String, String)>, makefile: &Makefile, options: &ScanOptions) -> Vec<Diagnostic> {
+    // The files to follow go through a queue shared by a fixed number of workers, starting
+    // with the entrypoints. Every executable has its own visited files
+    let tasks: Vec<Follow> = entrypoints
+        .into_iter()
+        .map(|(start, contents)| {
+            let file = FilePath::new(start.clone());
+            options.remember(&file, &contents);
+            let visited = Arc::new(Visited::new());
+            first_visit(&visited, options.files.identity(&file), false);
+            Follow {
+                start: Arc::from(start),
+                file,
+                conditional: false,
+                visited,
+            }
+        })
+        .collect();
+    let problems: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+    pool::run_tasks(options.jobs, tasks, |task, queue| {
+        if let Err(found) = follow(task, makefile, options, queue) {
+            problems.lock().unwrap().extend(found);
+        }
+    });
+    problems.into_inner().unwrap()
+}
+
+/// Adds to the makefile the files defining the symbols used by each entrypoint,
+/// and the symbols which nothing defines, giving back the problems found on the way:
+/// the symbols several files define and the includes of the linked files which cannot be followed
+fn link_by_symbols(entrypoints: &[(String, String)], makefile: &Makefile, options: &ScanOptions) -> Vec<Diagnostic> {
+    let index = SymbolIndex::build(".", &options.defines, options.unknown_macros, options.store.as_deref());
+    let mut problems = Vec::new();
+    entrypoints.iter().for_each(|(start, _)| {
+        let link_set = index.link_set(start, &options.defines, options.unknown_macros);
+        link_set.ambiguous.into_iter().for_each(|(symbol, definers)| {
+            let message = format!("uses {}, which several C files define: {} is linked", symbol, definers[0]);
+            let severity = if options.strict { Severity::Error } else { Severity::Warning };
+            let problem = definers
+                .iter()
+                .fold(Diagnostic::new(severity, "ambiguous-definition", message).at(start, None), |problem, definer| {
+                    problem.note(format!("{} defines {}", definer, symbol))
+                });
+            problems.push(problem);
+        });
+        std::iter::once(start).chain(link_set.sources.iter()).for_each(|source| {
+            match options.unit_of(&FilePath::new(source.clone())) {
+                Ok(unit) => problems.extend(unit.problems.iter().chain(unit.missing.iter()).cloned()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    let problem = Diagnostic::error("unreadable-file", format!("cannot be read: {}", e));
+                    problems.push(problem.at(&normalize(source), None));
                 }
+            }
+        });
+        link_set
+            .sources
+            .into_iter()
+            .for_each(|source| makefile.add_dependency(start, source, false));
+        makefile.add_unresolved(start, link_set.unresolved);
+    });
+    problems
+}
 
-                // Use both ".c" and ".h" extensions to traverse the tree
-                replace_with_c(&mut head);
+/// Replaces the ".h" at the end of the file name
+/// with a ".c" for the linker, None when it is not a header
+#[inline]
+fn c_file_of(header: &str) -> Option<String> {
+    header.strip_suffix(".h").map(|stem| format!("{}.c", stem))
+}
 
-                // Must add the parent node into the path as well
-                let further_path = FilePath::new(head.clone());
-                let further_path = FilePath::compose_ref(header, further_path);
 
-                let makefile_c = Arc::clone(&makefile);
+/// The files already followed from an executable, by identity, with whether they were
+/// only reached through #if branches on unknown macros
+type Visited = CHashMap<PathBuf, bool>;
 
-                // Traverse if header not currently in
-                if !makefile.has_dependency(&start, &further_path.combined()) {
-                    let start_c = start.clone();
-                    traversing_threads.push(thread::spawn(move || {
-                        traverse(start_c.clone(), &further_path, Arc::clone(&makefile_c));
+/// Marks the file as followed, telling whether it has to be: it was not yet, or only through
+/// #if branches on unknown macros and now it is reached without them
+fn first_visit(visited: &Visited, identity: PathBuf, conditional: bool) -> bool {
+    let mut follow = false;
+    visited.alter(identity, |was| match was {
+        Some(false) => Some(false),
+        Some(true) if conditional => Some(true),
+        _ => {
+            follow = true;
+            Some(conditional)
+        }
+    });
+    follow
+}
 
-                        let mut further_header = further_path.clone();
-                        further_header.file_to_header();
-                        traverse(start_c.clone(), &further_header, Arc::clone(&makefile_c));
-                    }));
-                }
+/// A C file to follow the includes of, for the executable with the given entrypoint
+struct Follow {
+    start: Arc<str>,
+    file: FilePath,
+    /// Whether the file is only reached through #if branches on unknown macros
+    conditional: bool,
+    visited: Arc<Visited>,
+}
 
+/// Links the C file to the executable, and queues the C files implementing the headers the
+/// preprocessor goes through for it. Every C file is followed once per executable.
+/// The includes which cannot be followed are skipped and given back as problems
+fn follow(
+    task: Follow,
+    makefile: &Makefile,
+    options: &ScanOptions,
+    queue: &WorkQueue<Follow>,
+) -> Result<(), Vec<Diagnostic>> {
+    let Follow {
+        start,
+        file,
+        conditional,
+        visited,
+    } = task;
+    let path = file.combined();
+    let unit = match options.unit_of(&file) {
+        Ok(unit) => unit,
+        // Only the explicit implementations are not checked to exist when a header is included
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let message = format!("{} is given as the implementation of a header but does not exist", normalize(&path));
+            let severity = if options.strict { Severity::Error } else { Severity::Warning };
+            return Err(vec![Diagnostic::new(severity, "missing-implementation", message).at(CONFIG_FILE, None)]);
+        }
+        Err(e) => {
+            let problem = Diagnostic::error("unreadable-file", format!("cannot be read: {}", e)).at(&normalize(&path), None);
+            return Err(vec![problem]);
+        }
+    };
+    if path.ends_with(".c") && path != *start {
+        makefile.add_dependency(&start, path.clone(), conditional);
+    }
+    unit.found.iter().for_each(|(via, header)| makefile.add_found_header(via, header.clone()));
+    for (identity, file, linked_conditionally) in unit.links.iter() {
+        let conditional = conditional || *linked_conditionally;
+        if first_visit(&visited, identity.clone(), conditional) {
+            queue.push(Follow {
+                start: Arc::clone(&start),
+                file: file.clone(),
+                conditional,
+                visited: Arc::clone(&visited),
             });
+        }
+    }
+    let mut problems = unit.problems.clone();
+    // A C file linked only through #if branches on unknown macros may never be compiled
+    if !conditional {
+        problems.extend(unit.missing.iter().cloned());
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
 
-            traversing_threads.into_iter().for_each(|t| { let _ = t.join(); });
+/// One include on the way from an executable to a C file it links: (including file, line, included header)
+pub type IncludeStep = (String, usize, String);
 
+/// Why a C file is linked into an executable: it implements the last header of the chain of includes
+#[derive(Debug)]
+pub struct Explanation {
+    pub source: String,
+    pub chain: Vec<IncludeStep>,
+    /// Whether the chain goes through #if branches on unknown macros
+    pub conditional: bool,
+}
+
+/// Explains every C file the includes of the entrypoint link, with the shortest chain of
+/// includes reaching it, in the order they are found
+pub fn explain(entrypoint: &str, options: &ScanOptions) -> Vec<Explanation> {
+    let mut explanations: Vec<Explanation> = Vec::new();
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut queue = VecDeque::new();
+    let entrypoint = normalize(entrypoint);
+    visited.insert(entrypoint.clone());
+    queue.push_back((FilePath::new(entrypoint), Vec::new(), false));
+    while let Some((file, chain, conditional)) = queue.pop_front() {
+        let unit = match options.unit_of(&file) {
+            Ok(unit) => unit,
+            Err(_) => continue,
+        };
+        for (index, include) in unit.includes.iter().enumerate() {
+            let conditional = conditional || include.conditional;
+            for next in options.implementations_of(&include.header).iter().cloned() {
+                let path = normalize(&next.combined());
+                if !visited.insert(path.clone()) {
+                    continue;
+                }
+                let mut chain = chain.clone();
+                chain.extend(unit.chain(index));
+                explanations.push(Explanation {
+                    source: path,
+                    chain: chain.clone(),
+                    conditional,
+                });
+                queue.push_back((next, chain, conditional));
+            }
         }
-        Err(e) => {
-            // println!("This file does not exist, either it is the .c version of a header which exists,\
-            // or it is an error with your #include setup: {}", header.combined());
+    }
+    explanations
+}
+
+/// An include of the project: the including file includes the project header at the given line
+#[derive(Debug, Clone)]
+pub struct IncludeEdge {
+    pub including: String,
+    pub line: usize,
+    pub included: String,
+    /// Whether the include is in an #if branch on unknown macros
+    pub conditional: bool,
+}
+
+/// Every include between the project files reached from the entrypoints, following the
+/// headers and the C files implementing them like the scan does, in the order they are found.
+/// System headers are left out
+pub fn include_graph(entrypoints: &[String], options: &ScanOptions) -> Vec<IncludeEdge> {
+    let mut edges: Vec<IncludeEdge> = Vec::new();
+    let mut found: HashSet<(String, usize, String)> = HashSet::new();
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut queue = VecDeque::new();
+    entrypoints.iter().for_each(|entrypoint| {
+        let entrypoint = normalize(entrypoint);
+        if visited.insert(entrypoint.clone()) {
+            queue.push_back(FilePath::new(entrypoint));
+        }
+    });
+    while let Some(file) = queue.pop_front() {
+        let unit = match options.unit_of(&file) {
+            Ok(unit) => unit,
+            Err(_) => continue,
+        };
+        for include in unit.includes.iter() {
+            // Every C file including a header goes through its includes again
+            if found.insert((include.including.clone(), include.include.line, include.included.clone())) {
+                edges.push(IncludeEdge {
+                    including: include.including.clone(),
+                    line: include.include.line,
+                    included: include.included.clone(),
+                    conditional: include.include.conditional,
+                });
+            }
+        }
+        unit.links
+            .iter()
+            .filter(|(_, next, _)| visited.insert(normalize(&next.combined())))
+            .for_each(|(_, next, _)| queue.push_back(next.clone()));
+    }
+    edges
+}
+
+/// How many include cycles are looked for at most: there can be exponentially many
+pub static MAX_CYCLES: usize = 100;
+
+/// Every include cycle of the graph (every elementary circuit, with Johnson's algorithm), as the
+/// includes going around it from the first file of the cycle reached. Each cycle is found once,
+/// however many files include its files
+pub fn include_cycles(includes: &[IncludeEdge]) -> Vec<Vec<IncludeEdge>> {
+    // The files in the order they are reached
+    let mut order: HashMap<&str, usize> = HashMap::new();
+    includes.iter().for_each(|edge| {
+        for file in [edge.including.as_str(), edge.included.as_str()] {
+            let next = order.len();
+            order.entry(file).or_insert(next);
+        }
+    });
+    let mut edges: HashMap<&str, Vec<&IncludeEdge>> = HashMap::new();
+    includes
+        .iter()
+        .for_each(|edge| edges.entry(edge.including.as_str()).or_default().push(edge));
+    let mut files: Vec<&str> = order.keys().copied().collect();
+    files.sort_by_key(|file| order[file]);
+    let mut cycles = Vec::new();
+    for (index, start) in files.iter().enumerate() {
+        // Only the cycles whose first file is start: the ones through earlier files were found already
+        let mut search = CycleSearch {
+            start,
+            first: index,
+            order: &order,
+            edges: &edges,
+            chain: Vec::new(),
+            blocked: HashSet::new(),
+            blocking: HashMap::new(),
+            cycles: &mut cycles,
+        };
+        search.circuit(start);
+        if cycles.len() >= MAX_CYCLES {
+            break;
         }
+    }
+    cycles
+}
 
-    };
+/// The search for the cycles going through the file start and files reached after it
+struct CycleSearch<'a, 'c> {
+    start: &'a str,
+    first: usize,
+    order: &'a HashMap<&'a str, usize>,
+    edges: &'a HashMap<&'a str, Vec<&'a IncludeEdge>>,
+    /// The includes from start to the current file
+    chain: Vec<&'a IncludeEdge>,
+    /// The files which cannot lead back to start right now
+    blocked: HashSet<&'a str>,
+    /// The files to unblock with the file they are keyed by
+    blocking: HashMap<&'a str, HashSet<&'a str>>,
+    cycles: &'c mut Vec<Vec<IncludeEdge>>,
+}
+
+impl<'a, 'c> CycleSearch<'a, 'c> {
+    /// Follows the includes of file, telling whether a cycle back to start was found
+    fn circuit(&mut self, file: &'a str) -> bool {
+        let mut found = false;
+        self.blocked.insert(file);
+        let (order, first) = (self.order, self.first);
+        let edges = self.edges.get(file).map(|edges| edges.as_slice()).unwrap_or_default();
+        for edge in edges.iter().filter(|edge| order[edge.included.as_str()] >= first) {
+            if self.cycles.len() >= MAX_CYCLES {
+                break;
+            }
+            if edge.included == self.start {
+                let mut cycle: Vec<IncludeEdge> = self.chain.iter().map(|step| (*step).clone()).collect();
+                cycle.push((*edge).clone());
+                self.cycles.push(cycle);
+                found = true;
+            } else if !self.blocked.contains(edge.included.as_str()) {
+                self.chain.push(edge);
+                found |= self.circuit(&edge.included);
+                self.chain.pop();
+            }
+        }
+        if found {
+            self.unblock(file);
+        } else {
+            for edge in edges {
+                self.blocking.entry(edge.included.as_str()).or_default().insert(file);
+            }
+        }
+        found
+    }
+
+    fn unblock(&mut self, file: &'a str) {
+        self.blocked.remove(file);
+        for blocked in self.blocking.remove(file).unwrap_or_default() {
+            if self.blocked.contains(blocked) {
+                self.unblock(blocked);
+            }
+        }
+    }
 }
 
-/// Removes the '#include' and the quotes that wrap the header from a given header string
-/// PRE: str must start with #include
-fn remove_include(str: String) -> String {
-    // Sanity check
-    if !str.starts_with("#include") {
-        panic!("You asked me to remove the '#include' from a String which doesn't contain it");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, HeadersConfig, MirrorRule};
+
+    /// A project of its own in the temporary directory with the given (empty) files, by its root
+    fn project(name: &str, files: &[&str]) -> String {
+        let root = std::env::temp_dir().join(format!("nofile-{}-{}", std::process::id(), name));
+        files.iter().for_each(|file| {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        });
+        root.to_string_lossy().into_owned()
+    }
+
+    fn include(kind: IncludeKind, path: &str) -> Include {
+        Include {
+            kind,
+            path: path.to_string(),
+            line: 1,
+            conditional: false,
+        }
+    }
+
+    /// Where the include of the file resolves, relative to the root
+    fn resolved(options: &ScanOptions, root: &str, include: &Include, including: &str) -> Option<(String, SearchDir)> {
+        let including = FilePath::new(format!("{}/{}", root, including));
+        options
+            .resolve(include, &including)
+            .map(|(path, via)| (path.combined().trim_start_matches(root).trim_start_matches('/').to_string(), via))
+    }
+
+    #[test]
+    fn quoted_includes_are_found_next_to_the_including_file() {
+        let root = project("local", &["src/main.c", "src/util.h", "src/stdafx.h"]);
+        let options = ScanOptions::default();
+        let util = include(IncludeKind::Quoted, "util.h");
+        assert_eq!(resolved(&options, &root, &util, "src/main.c"), Some(("src/util.h".to_string(), SearchDir::Local)));
+        // A project header is one of the project whatever its name
+        let stdafx = include(IncludeKind::Quoted, "stdafx.h");
+        assert_eq!(resolved(&options, &root, &stdafx, "src/main.c"), Some(("src/stdafx.h".to_string(), SearchDir::Local)));
+    }
+
+    #[test]
+    fn headers_found_nowhere_are_system_headers() {
+        let root = project("system", &["main.c"]);
+        let options = ScanOptions::default();
+        assert_eq!(resolved(&options, &root, &include(IncludeKind::Angled, "stdio.h"), "main.c"), None);
+        assert_eq!(resolved(&options, &root, &include(IncludeKind::Quoted, "missing.h"), "main.c"), None);
+    }
+
+    #[test]
+    fn angled_includes_are_not_looked_for_next_to_the_file() {
+        let root = project("angled", &["main.c", "util.h"]);
+        let options = ScanOptions::default();
+        assert_eq!(resolved(&options, &root, &include(IncludeKind::Angled, "util.h"), "main.c"), None);
+    }
+
+    #[test]
+    fn searches_the_local_then_the_iquote_then_the_include_dirs() {
+        let root = project("order", &["src/main.c", "src/a.h", "quote/a.h", "quote/b.h", "inc/a.h", "inc/b.h", "inc/c.h"]);
+        let options = ScanOptions {
+            quote_dirs: vec![format!("{}/quote", root)],
+            include_dirs: vec![format!("{}/inc", root)],
+            ..ScanOptions::default()
+        };
+        let found = |path: &str| resolved(&options, &root, &include(IncludeKind::Quoted, path), "src/main.c");
+        assert_eq!(found("a.h"), Some(("src/a.h".to_string(), SearchDir::Local)));
+        assert_eq!(found("b.h"), Some(("quote/b.h".to_string(), SearchDir::Quote(format!("{}/quote", root)))));
+        assert_eq!(found("c.h"), Some(("inc/c.h".to_string(), SearchDir::Include(format!("{}/inc", root)))));
+    }
+
+    #[test]
+    fn iquote_dirs_are_only_for_quoted_includes() {
+        let root = project("iquote", &["main.c", "quote/a.h", "inc/a.h"]);
+        let options = ScanOptions {
+            quote_dirs: vec![format!("{}/quote", root)],
+            include_dirs: vec![format!("{}/inc", root)],
+            ..ScanOptions::default()
+        };
+        let angled = include(IncludeKind::Angled, "a.h");
+        assert_eq!(resolved(&options, &root, &angled, "main.c"), Some(("inc/a.h".to_string(), SearchDir::Include(format!("{}/inc", root)))));
+    }
+
+    #[test]
+    fn headers_of_the_isystem_dirs_are_system_headers() {
+        let root = project("isystem", &["main.c", "sys/vendor.h"]);
+        let options = ScanOptions {
+            system_dirs: vec![format!("{}/sys", root)],
+            ..ScanOptions::default()
+        };
+        assert_eq!(resolved(&options, &root, &include(IncludeKind::Quoted, "vendor.h"), "main.c"), None);
+    }
+
+    #[test]
+    fn the_search_flags_are_in_the_order_gcc_searches() {
+        let options = ScanOptions {
+            quote_dirs: vec!["quote".to_string()],
+            include_dirs: vec!["inc".to_string(), "third/inc".to_string()],
+            system_dirs: vec!["sys".to_string()],
+            ..ScanOptions::default()
+        };
+        assert_eq!(options.include_flags(), vec!["-iquote quote", "-Iinc", "-Ithird/inc", "-isystem sys"]);
+    }
+
+    #[test]
+    fn normalizes_paths_lexically() {
+        assert_eq!(normalize("src/../include//./foo.h"), "include/foo.h");
+        assert_eq!(normalize("./main.c"), "main.c");
+        assert_eq!(normalize("../lib/a.c"), "../lib/a.c");
+        assert_eq!(normalize("/tmp/./x/../y.c"), "/tmp/y.c");
+    }
+
+    #[test]
+    fn the_sibling_of_a_header_implements_it() {
+        let root = project("sibling", &["net/io.h", "net/io.c", "net/only.h"]);
+        assert_eq!(SiblingResolver.implementations(&format!("{}/net/io.h", root)), Some(vec![format!("{}/net/io.c", root)]));
+        assert_eq!(SiblingResolver.implementations(&format!("{}/net/only.h", root)), None);
+    }
+
+    #[test]
+    fn overrides_map_a_header_to_several_files() {
+        let overrides = [("./include/foo.h".to_string(), vec!["src/foo_io.c".to_string(), "src/../src/foo_math.c".to_string()])];
+        let resolver = OverrideResolver::new(&overrides.iter().cloned().collect());
+        assert_eq!(
+            resolver.implementations("include/foo.h"),
+            Some(vec!["src/foo_io.c".to_string(), "src/foo_math.c".to_string()])
+        );
+        assert_eq!(resolver.implementations("include/bar.h"), None);
+    }
+
+    #[test]
+    fn mirror_rules_map_header_directories_to_source_directories() {
+        let root = project("mirror", &["include/net/io.h", "src/net/io.c"]);
+        let resolver = MirrorResolver::new(vec![MirrorRule {
+            from: format!("{}/include/**", root),
+            to: format!("{}/src/**", root),
+        }]);
+        assert_eq!(resolver.implementations(&format!("{}/include/net/io.h", root)), Some(vec![format!("{}/src/net/io.c", root)]));
+        assert_eq!(resolver.implementations(&format!("{}/other/io.h", root)), None);
+    }
+
+    #[test]
+    fn the_file_with_the_same_name_in_the_most_similar_directory_implements_a_header() {
+        let root = project("basename", &["include/net/util.h", "src/net/util.c", "src/fs/util.c", "a/log.c", "b/log.c"]);
+        let resolver = BasenameResolver::new(&root);
+        assert_eq!(
+            resolver.implementations(&format!("{}/include/net/util.h", root)),
+            Some(vec![format!("{}/src/net/util.c", root)])
+        );
+        // Ambiguous, nothing is better than the wrong file
+        assert_eq!(resolver.implementations(&format!("{}/include/log.h", root)), None);
+    }
+
+    #[test]
+    fn a_file_with_the_same_name_defining_main_never_implements_a_header() {
+        let root = project("basename-main", &["src/main.c", "include/util.h", "tests/util.c"]);
+        fs::write(format!("{}/tests/util.c", root), "int main(void) { return 0; }\n").unwrap();
+        let header = format!("{}/include/util.h", root);
+        assert_eq!(BasenameResolver::new(&root).implementations(&header), None);
+        // And the same name only counts when asked for
+        fs::write(format!("{}/tests/util.c", root), "int util(void) { return 0; }\n").unwrap();
+        let mut config = HeadersConfig::default();
+        assert!(Implementations::from_config(&config, &root).find(&FilePath::new(header.clone())).is_empty());
+        config.basename = true;
+        let found = Implementations::from_config(&config, &root).find(&FilePath::new(header));
+        assert_eq!(found.iter().map(FilePath::combined).collect::<Vec<String>>(), vec![format!("{}/tests/util.c", root)]);
+    }
+
+    #[test]
+    fn the_explicit_implementations_come_first() {
+        let root = project("first", &["util.h", "util.c", "util_impl.c"]);
+        let header = format!("{}/util.h", root);
+        let config = HeadersConfig {
+            implementations: [(header.clone(), vec![format!("{}/util_impl.c", root)])].iter().cloned().collect(),
+            ..HeadersConfig::default()
+        };
+        let found = Implementations::from_config(&config, &root).find(&FilePath::new(header));
+        let found: Vec<String> = found.iter().map(FilePath::combined).collect();
+        assert_eq!(found, vec![format!("{}/util_impl.c", root)]);
+    }
+
+    #[test]
+    fn an_explicit_implementation_which_does_not_exist_is_reported() {
+        let root = project("missing-implementation", &["util.h"]);
+        let main = format!("{}/main.c", root);
+        let contents = "#include \"util.h\"\n".to_string();
+        fs::write(&main, &contents).unwrap();
+        let config = HeadersConfig {
+            implementations: [(format!("{}/util.h", root), vec![format!("{}/util_impl.c", root)])].iter().cloned().collect(),
+            ..HeadersConfig::default()
+        };
+        let options = ScanOptions {
+            implementations: Implementations::from_config(&config, &root),
+            ..ScanOptions::default()
+        };
+        let makefile = run(vec![(main, contents)], options);
+        let rules: Vec<&str> = makefile.diagnostics().iter().map(|problem| problem.rule).collect();
+        assert_eq!(rules, vec!["missing-implementation"]);
+        assert!(makefile.diagnostics()[0].message.contains("util_impl.c"));
+    }
+
+    #[test]
+    fn the_configuration_gives_the_build_options() {
+        let mut config = Config::default();
+        config.build.cc = Some("clang".to_string());
+        config.build.include = vec!["inc".to_string()];
+        config.build.defines = vec!["LEVEL=2".to_string()];
+        config.build.undefines = vec!["USE_GUI".to_string()];
+        config.build.unknown_macros = Some(UnknownMacros::Both);
+        let options = ScanOptions::from_config(&config);
+        assert_eq!(options.compiler, "clang");
+        assert_eq!(options.cflags, DEFAULT_CFLAGS.iter().map(|flag| flag.to_string()).collect::<Vec<String>>());
+        assert_eq!(options.cpp_flags(), vec!["-DLEVEL=2".to_string(), "-UUSE_GUI".to_string(), "-Iinc".to_string()]);
+        assert_eq!(options.unknown_macros, UnknownMacros::Both);
+    }
+
+    #[test]
+    fn globs_match_names_and_directories() {
+        assert!(glob_match("src/*.c", "src/main.c"));
+        assert!(!glob_match("src/*.c", "src/net/io.c"));
+        assert!(glob_match("**/*_test.c", "tests/unit/io_test.c"));
+        assert!(glob_match("**/*_test.c", "io_test.c"));
+        assert!(glob_match("src/**", "src/net/io.c"));
+        assert!(glob_match("./src/?o.c", "src/io.c"));
+        assert!(!glob_match("src/?o.c", "src/ino.c"));
+    }
+
+    #[test]
+    fn discovers_the_live_definitions_of_main() {
+        let root = project("discover", &["src/main.c", "src/util.c", "tools/gen.c", "tests/io_test.c"]);
+        let main = "int main(void) { return 0; }\n";
+        for file in &["src/main.c", "tools/gen.c", "tests/io_test.c"] {
+            fs::write(format!("{}/{}", root, file), main).unwrap();
+        }
+        fs::write(format!("{}/src/util.c", root), format!("#ifdef STANDALONE\n{}#endif\n", main)).unwrap();
+        let discovery = Discovery { exclude: vec!["**/*_test.c".to_string()], ..Discovery::default() };
+        assert_eq!(
+            discover(&root, &discovery, &Defines::default(), UnknownMacros::False),
+            vec![format!("{}/src/main.c", root), format!("{}/tools/gen.c", root)]
+        );
+    }
+
+    #[test]
+    fn paths_match_the_directories_they_are_under() {
+        assert!(path_matches("tools", "tools/gen/main.c"));
+        assert!(path_matches("./tools/", "tools/main.c"));
+        assert!(!path_matches("tools", "toolsets/main.c"));
+        assert!(path_matches("**/*_bench.c", "src/io_bench.c"));
+    }
+
+    fn edge(including: &str, line: usize, included: &str) -> IncludeEdge {
+        IncludeEdge {
+            including: including.to_string(),
+            line,
+            included: included.to_string(),
+            conditional: false,
+        }
+    }
+
+    /// The cycles as "file:line" steps
+    fn cycles(includes: &[IncludeEdge]) -> Vec<Vec<String>> {
+        include_cycles(includes)
+            .iter()
+            .map(|cycle| cycle.iter().map(|edge| format!("{}:{}", edge.including, edge.line)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn no_includes_no_cycles() {
+        assert!(cycles(&[edge("main.c", 1, "a.h"), edge("a.h", 1, "b.h"), edge("main.c", 2, "b.h")]).is_empty());
+    }
+
+    #[test]
+    fn finds_a_header_including_itself() {
+        assert_eq!(cycles(&[edge("a.h", 3, "a.h")]), vec![vec!["a.h:3"]]);
+    }
+
+    #[test]
+    fn finds_the_includes_going_round() {
+        let includes = [edge("main.c", 1, "a.h"), edge("a.h", 2, "b.h"), edge("b.h", 4, "a.h")];
+        assert_eq!(cycles(&includes), vec![vec!["a.h:2", "b.h:4"]]);
+    }
+
+    #[test]
+    fn every_file_is_read_and_parsed_once() {
+        let root = project("once", &["src/util.c"]);
+        let path = format!("{}/src/util.c", root);
+        fs::write(&path, "#include \"util.h\"\n").unwrap();
+        let options = ScanOptions::default();
+        let first = options.directives_of(&FilePath::new(path.clone())).unwrap();
+        fs::write(&path, "").unwrap();
+        let again = options.directives_of(&FilePath::new(format!("{}/src/../src/./util.c", root))).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(again[0].body, "\"util.h\"");
+        assert!(options.directives_of(&FilePath::new(format!("{}/missing.c", root))).is_err());
+    }
+
+    #[test]
+    fn a_scan_parses_every_file_once_however_many_executables_include_it() {
+        let root = project("parsed-once", &["util.c", "config.h"]);
+        let files = [
+            ("main.c", "#include \"util.h\"\n"),
+            ("server.c", "#include \"config.h\"\n#include \"util.h\"\n"),
+            ("util.h", "#include \"config.h\"\n#ifdef VERBOSE\n#include \"log.h\"\n#endif\n"),
+        ];
+        files.iter().for_each(|(file, contents)| fs::write(format!("{}/{}", root, file), contents).unwrap());
+        let entrypoints = files[..2]
+            .iter()
+            .map(|(file, contents)| (format!("{}/{}", root, file), contents.to_string()))
+            .collect();
+        let store = Arc::new(ScanCache::default());
+        let options = ScanOptions {
+            store: Some(Arc::clone(&store)),
+            ..ScanOptions::default()
+        };
+        run(entrypoints, options);
+        // main.c, server.c, util.h, config.h and util.c
+        assert_eq!(store.stats(), (5, 0));
+    }
+
+    #[test]
+    fn the_macros_of_a_header_only_reach_the_files_including_it() {
+        let root = project("macros", &["sock.h", "sock.c"]);
+        let files = [
+            ("main.c", "#ifdef _WIN32\n#include \"win.h\"\n#endif\n#include \"net.h\"\n"),
+            ("server.c", "#include \"win.h\"\n#include \"net.h\"\n"),
+            ("win.h", "#define USE_WINSOCK\n"),
+            ("net.h", "#ifdef USE_WINSOCK\n#include \"sock.h\"\n#endif\n"),
+        ];
+        files.iter().for_each(|(file, contents)| fs::write(format!("{}/{}", root, file), contents).unwrap());
+        let entrypoints = ["main.c", "server.c"]
+            .iter()
+            .map(|file| (format!("{}/{}", root, file), files.iter().find(|(name, _)| name == file).unwrap().1.to_string()))
+            .collect();
+        let makefile = run(entrypoints, ScanOptions::default());
+        let link_sets = makefile.link_sets();
+        assert_eq!(link_sets[0].1, Vec::<String>::new());
+        assert_eq!(link_sets[1].1, vec![format!("{}/sock.c", root)]);
+    }
+
+    #[test]
+    fn the_scan_gives_back_every_problem_at_its_place() {
+        let root = project("problems", &["table.txt", "data.bin"]);
+        let main = format!("{}/main.c", root);
+        let contents = "#include \"table.txt\"\nint x;\n#include \"data.bin\"\n".to_string();
+        fs::write(&main, &contents).unwrap();
+        let makefile = run(vec![(main.clone(), contents)], ScanOptions::default());
+        let problems = makefile.diagnostics();
+        let locations: Vec<Option<String>> = problems.iter().map(Diagnostic::location).collect();
+        assert_eq!(locations, vec![Some(format!("{}:1", main)), Some(format!("{}:3", main))]);
+        assert!(problems.iter().all(Diagnostic::is_error));
+    }
+
+    #[test]
+    fn a_scan_with_errors_still_gives_what_it_found() {
+        let root = project("errors-and-cycles", &["table.txt"]);
+        let main = format!("{}/main.c", root);
+        let contents = "#include \"table.txt\"\n#include \"a.h\"\n".to_string();
+        fs::write(&main, &contents).unwrap();
+        fs::write(format!("{}/a.h", root), "#include \"b.h\"\n").unwrap();
+        fs::write(format!("{}/b.h", root), "#include \"a.h\"\n").unwrap();
+        let makefile = run(vec![(main, contents)], ScanOptions::default());
+        assert!(makefile.diagnostics().iter().any(Diagnostic::is_error));
+        // So the cycles are reported along with the errors
+        assert_eq!(include_cycles(makefile.includes()).len(), 1);
+    }
+
+    /// A main.c including a header which is nowhere
+    fn missing_include(name: &str) -> (String, String) {
+        let root = project(name, &["main.c"]);
+        let main = format!("{}/main.c", root);
+        let contents = "#include <nofile_angled.h>\n#if SOMETHING\n#include \"maybe.h\"\n#endif\n#include \"typo.h\"\n".to_string();
+        fs::write(&main, &contents).unwrap();
+        (main, contents)
+    }
+
+    #[test]
+    fn quoted_includes_found_nowhere_are_reported() {
+        let (main, contents) = missing_include("missing");
+        let options = ScanOptions { include_dirs: vec!["inc".to_string()], unknown_macros: UnknownMacros::Both, ..ScanOptions::default() };
+        let makefile = run(vec![(main.clone(), contents)], options);
+        let problems = makefile.diagnostics();
+        assert_eq!(problems.len(), 1);
+        assert!(!problems[0].is_error());
+        assert_eq!(problems[0].message, "\"typo.h\" is included but found nowhere");
+        assert_eq!(problems[0].location(), Some(format!("{}:5", main)));
+        assert_eq!(problems[0].notes, vec![format!("searched in {}, inc", main.trim_end_matches("/main.c"))]);
+    }
+
+    #[test]
+    fn quoted_includes_found_nowhere_are_errors_when_strict() {
+        let (main, contents) = missing_include("strict");
+        let options = ScanOptions { strict: true, ..ScanOptions::default() };
+        let problems = run(vec![(main, contents)], options).diagnostics().to_vec();
+        assert!(problems.len() == 1 && problems[0].is_error());
+    }
+
+    #[test]
+    fn only_headers_have_a_c_file() {
+        assert_eq!(c_file_of("include/net/io.h"), Some("include/net/io.c".to_string()));
+        assert_eq!(c_file_of("table.def"), None);
+        assert_eq!(SiblingResolver.implementations("table.def"), None);
+        assert_eq!(BasenameResolver::new(".").implementations("table.def"), None);
+    }
+
+    #[test]
+    fn finds_every_cycle_through_the_same_files() {
+        let includes = [
+            edge("main.c", 1, "a.h"),
+            edge("a.h", 1, "b.h"),
+            edge("a.h", 2, "c.h"),
+            edge("b.h", 1, "c.h"),
+            edge("c.h", 1, "a.h"),
+        ];
+        assert_eq!(cycles(&includes), vec![vec!["a.h:1", "b.h:1", "c.h:1"], vec!["a.h:2", "c.h:1"]]);
+    }
+
+    #[test]
+    fn finds_each_cycle_once() {
+        let includes = [
+            edge("a.h", 1, "b.h"),
+            edge("b.h", 1, "a.h"),
+            edge("b.h", 2, "c.h"),
+            edge("c.h", 1, "b.h"),
+            edge("d.h", 1, "b.h"),
+        ];
+        assert_eq!(cycles(&includes), vec![vec!["a.h:1", "b.h:1"], vec!["b.h:2", "c.h:1"]]);
+    }
+
+    #[test]
+    fn stops_at_the_most_cycles_looked_for() {
+        // Every header including every other one: thousands of cycles
+        let headers = ["a.h", "b.h", "c.h", "d.h", "e.h", "f.h", "g.h"];
+        let includes: Vec<IncludeEdge> = headers
+            .iter()
+            .flat_map(|including| {
+                let others = headers.iter().filter(move |included| *included != including);
+                others.map(move |included| edge(including, 1, included))
+            })
+            .collect();
+        assert_eq!(include_cycles(&includes).len(), MAX_CYCLES);
     }
-    let valids = str.chars().enumerate().filter(|(i,_)| 
-        // Exclude '#include "' and the last "
-        i > &"#include ".len() && i != &(str.len() - 1)
-    );
-    return valids.map(|(_, c)| c).collect();
 }
@@ -0,0 +1,833 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use serde::Deserialize;
+
+use crate::lexer::{self, Directive, Include};
+
+/// What we know about a macro
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Macro {
+    /// Object-like macro with its replacement text
+    Object(String),
+    /// Function-like macro, we never expand it
+    Function,
+    /// Explicitly undefined (by -U or #undef)
+    Undefined,
+    /// Defined or undefined in #if branches on unknown macros (or by a file included in one),
+    /// so maybe not: every branch depending on it is followed
+    Unknown,
+}
+
+/// How to treat macros which nobody ever defined or undefined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownMacros {
+    /// Treat them as undefined, like the C preprocessor does
+    False,
+    /// Treat them as defined to 1
+    True,
+    /// Follow every branch which depends on them, marking what we find there as conditional
+    Both,
+}
+
+impl UnknownMacros {
+    /// Parses the value of --unknown-macros
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "false" => Some(UnknownMacros::False),
+            "true" => Some(UnknownMacros::True),
+            "both" => Some(UnknownMacros::Both),
+            _ => None,
+        }
+    }
+}
+
+/// The macro table of a translation unit. Macros which are not in the table are unknown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Defines(HashMap<String, Macro>);
+
+impl Defines {
+    pub fn new() -> Self {
+        Defines(HashMap::new())
+    }
+
+    /// Adds a definition in the -D format: "NAME" or "NAME=VALUE"
+    /// NAME on its own is defined to 1, like gcc does
+    pub fn define_arg(&mut self, arg: &str) {
+        match arg.find('=') {
+            Some(eq) => self.define(&arg[..eq], arg[eq + 1..].to_string()),
+            None => self.define(arg, "1".to_string()),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: String) {
+        self.0.insert(name.to_string(), Macro::Object(value));
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.0.insert(name.to_string(), Macro::Undefined);
+    }
+
+    /// Every macro with its value, in the same order whatever the table was built from
+    pub fn fingerprint(&self) -> String {
+        let mut macros: Vec<String> = self.0.iter().map(|(name, value)| format!("{}={:?}", name, value)).collect();
+        macros.sort();
+        macros.join(" ")
+    }
+
+    /// Makes the macro unknown, as what was done to it may not have happened
+    fn unsure(&mut self, name: &str) {
+        if !name.is_empty() {
+            self.0.insert(name.to_string(), Macro::Unknown);
+        }
+    }
+
+    /// Makes unknown every macro which other leaves differently, as what made the
+    /// difference may not have happened
+    fn unsure_of(&mut self, other: &Defines) {
+        let changed: Vec<String> = self
+            .0
+            .keys()
+            .chain(other.0.keys())
+            .filter(|name| self.get(name) != other.get(name))
+            .cloned()
+            .collect();
+        changed.iter().for_each(|name| self.unsure(name));
+    }
+
+    fn get(&self, name: &str) -> Option<&Macro> {
+        self.0.get(name)
+    }
+
+    /// Handles a #define directive body: "NAME replacement" or "NAME(args) replacement"
+    fn define_directive(&mut self, body: &str) {
+        let name_len = identifier_len(body);
+        if name_len == 0 {
+            return;
+        }
+        let (name, rest) = body.split_at(name_len);
+        if rest.starts_with('(') {
+            self.0.insert(name.to_string(), Macro::Function);
+        } else {
+            self.define(name, rest.trim().to_string());
+        }
+    }
+}
+
+/// Whether a region of the source is seen by the compiler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Liveness {
+    Live,
+    /// Only live for some values of the unknown macros
+    Maybe,
+    Dead,
+}
+
+impl Liveness {
+    fn of(condition: Option<bool>) -> Self {
+        match condition {
+            Some(true) => Liveness::Live,
+            Some(false) => Liveness::Dead,
+            None => Liveness::Maybe,
+        }
+    }
+
+    /// Liveness of a region nested inside a region with liveness self
+    fn inside(self, other: Liveness) -> Self {
+        match (self, other) {
+            (Liveness::Dead, _) | (_, Liveness::Dead) => Liveness::Dead,
+            (Liveness::Maybe, _) | (_, Liveness::Maybe) => Liveness::Maybe,
+            _ => Liveness::Live,
+        }
+    }
+}
+
+/// One #if ... #endif group
+struct Group {
+    /// Liveness of the region containing the group
+    outer: Liveness,
+    /// Liveness of the current branch of the group
+    branch: Liveness,
+    /// Some branch was certainly taken, so the rest are dead
+    taken: bool,
+    /// Some branch was possibly taken
+    maybe_taken: bool,
+}
+
+impl Group {
+    fn new(outer: Liveness) -> Self {
+        Group {
+            outer,
+            branch: Liveness::Dead,
+            taken: false,
+            maybe_taken: false,
+        }
+    }
+
+    /// Moves to the next branch (#if, #elif or #else) whose condition evaluated to condition
+    fn enter(&mut self, condition: Option<bool>) {
+        if self.taken {
+            self.branch = Liveness::Dead;
+            return;
+        }
+        self.branch = match condition {
+            Some(true) if self.maybe_taken => Liveness::Maybe,
+            _ => Liveness::of(condition),
+        };
+        match condition {
+            Some(true) => self.taken = true,
+            None => self.maybe_taken = true,
+            Some(false) => {}
+        }
+    }
+
+    fn liveness(&self) -> Liveness {
+        self.outer.inside(self.branch)
+    }
+}
+
+/// What the compiler sees of a C file
+#[derive(Debug, Default)]
+pub struct Preprocessed {
+    /// The includes in live #if branches
+    pub includes: Vec<Include>,
+    /// The live lines which are not directives, without comments
+    /// Lines in branches which depend on unknown macros are kept as well
+    pub code: String,
+}
+
+/// Splits the given C source (or header) into the includes in its live #if branches and its
+/// live code, tracking the #define and #undef directives of the file on top of the given defines.
+/// Includes in branches which depend on unknown macros are marked as conditional.
+/// The included files are not looked at
+pub fn preprocess(source: &str, header: bool, defines: &Defines, unknown: UnknownMacros) -> Preprocessed {
+    let lines = lexer::logical_lines(source);
+    let directives: Vec<Option<Directive>> = lines
+        .iter()
+        .map(|(logical, line)| lexer::directive_of(logical, *line))
+        .collect();
+    let mut defines = defines.clone();
+    let mut walk = Walk::new(directives.iter().flatten(), header, &mut defines, unknown);
+    let mut preprocessed = Preprocessed::default();
+    for ((logical, _), directive) in lines.iter().zip(&directives) {
+        match directive {
+            Some(directive) => walk.directive(directive, &mut |include, _| preprocessed.includes.push(include)),
+            None if walk.liveness() != Liveness::Dead => {
+                preprocessed.code.push_str(logical);
+                preprocessed.code.push('\n');
+            }
+            None => {}
+        }
+    }
+    preprocessed
+}
+
+/// Goes through the directives of a C file (or header) in order, like the preprocessor does,
+/// leaving the defines as the #define and #undef directives of the file leave them.
+/// include is called with every include in a live #if branch, in order, and does to the defines
+/// what the included file does. Includes in branches which depend on unknown macros are marked
+/// as conditional, and what they do to the defines makes the macros unknown
+pub fn walk(
+    directives: &[Directive],
+    header: bool,
+    defines: &mut Defines,
+    unknown: UnknownMacros,
+    mut include: impl FnMut(Include, &mut Defines),
+) {
+    let mut walk = Walk::new(directives.iter(), header, defines, unknown);
+    directives.iter().for_each(|directive| walk.directive(directive, &mut include));
+}
+
+/// Where the preprocessor is inside a file: the #if groups it is in and the macros so far
+struct Walk<'a> {
+    defines: &'a mut Defines,
+    unknown: UnknownMacros,
+    groups: Vec<Group>,
+}
+
+impl<'a> Walk<'a> {
+    /// Starts at the top of the file with the given directives
+    fn new<'d>(
+        directives: impl Iterator<Item = &'d Directive>,
+        header: bool,
+        defines: &'a mut Defines,
+        unknown: UnknownMacros,
+    ) -> Self {
+        // The include guard of a header is not defined the first time we see it,
+        // unless it is a macro given by the user
+        if let Some(guard) = include_guard(directives).filter(|guard| header && defines.get(guard).is_none()) {
+            defines.undefine(&guard);
+        }
+        Walk {
+            defines,
+            unknown,
+            groups: Vec::new(),
+        }
+    }
+
+    fn liveness(&self) -> Liveness {
+        self.groups.last().map_or(Liveness::Live, Group::liveness)
+    }
+
+    /// Goes past the directive, giving include the includes in live branches
+    fn directive(&mut self, directive: &Directive, include: &mut impl FnMut(Include, &mut Defines)) {
+        let current = self.liveness();
+        let unknown = self.unknown;
+        match directive.name.as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let mut group = Group::new(current);
+                if current != Liveness::Dead {
+                    let defines = &*self.defines;
+                    let condition = match directive.name.as_str() {
+                        "if" => evaluate(&directive.body, defines, unknown),
+                        "ifdef" => is_defined(&directive.body, defines, unknown),
+                        _ => is_defined(&directive.body, defines, unknown).map(|d| !d),
+                    };
+                    group.enter(condition);
+                }
+                self.groups.push(group);
+            }
+            "elif" | "else" => {
+                if let Some(group) = self.groups.last_mut() {
+                    if group.outer != Liveness::Dead {
+                        let condition = if directive.name == "else" {
+                            Some(true)
+                        } else {
+                            evaluate(&directive.body, self.defines, unknown)
+                        };
+                        group.enter(condition);
+                    }
+                }
+            }
+            "endif" => {
+                self.groups.pop();
+            }
+            "define" | "undef" if current != Liveness::Dead => {
+                let name = &directive.body[..identifier_len(&directive.body)];
+                if current == Liveness::Maybe {
+                    // We cannot tell whether this ran
+                    self.defines.unsure(name);
+                } else if directive.name == "define" {
+                    self.defines.define_directive(&directive.body);
+                } else {
+                    self.defines.undefine(name);
+                }
+            }
+            "include" if current != Liveness::Dead => {
+                if let Some(mut found) = directive.as_include() {
+                    found.conditional = current == Liveness::Maybe;
+                    if found.conditional {
+                        // The file may not be included at all, so whatever it does is unsure
+                        let mut maybe = self.defines.clone();
+                        include(found, &mut maybe);
+                        self.defines.unsure_of(&maybe);
+                    } else {
+                        include(found, self.defines);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The macro of the include guard when the file starts with "#ifndef NAME" and "#define NAME"
+fn include_guard<'d>(mut directives: impl Iterator<Item = &'d Directive>) -> Option<String> {
+    let (first, second) = (directives.next()?, directives.next()?);
+    if first.name != "ifndef" || second.name != "define" {
+        return None;
+    }
+    let guard = &first.body[..identifier_len(&first.body)];
+    let defined = &second.body[..identifier_len(&second.body)];
+    Some(guard.to_string()).filter(|guard| !guard.is_empty() && guard == defined)
+}
+
+/// Length of the identifier at the start of the string
+fn identifier_len(s: &str) -> usize {
+    s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len())
+}
+
+/// Whether the macro named at the start of body is defined, None if we do not know
+fn is_defined(body: &str, defines: &Defines, unknown: UnknownMacros) -> Option<bool> {
+    let name = &body.trim()[..identifier_len(body.trim())];
+    match defines.get(name) {
+        Some(Macro::Undefined) => Some(false),
+        Some(Macro::Unknown) => None,
+        Some(_) => Some(true),
+        None => unknown_value(unknown).map(|v| v != 0),
+    }
+}
+
+/// The value of an unknown macro under the given policy
+fn unknown_value(unknown: UnknownMacros) -> Option<i64> {
+    match unknown {
+        UnknownMacros::False => Some(0),
+        UnknownMacros::True => Some(1),
+        UnknownMacros::Both => None,
+    }
+}
+
+/// Evaluates an #if condition. None means the result depends on unknown macros.
+/// Expressions we cannot make sense of are treated like unknown macros
+fn evaluate(expression: &str, defines: &Defines, unknown: UnknownMacros) -> Option<bool> {
+    try_evaluate(expression, defines, unknown)
+        .unwrap_or_else(|| unknown_value(unknown))
+        .map(|v| v != 0)
+}
+
+/// Evaluates an #if expression, the outer None meaning it is malformed
+fn try_evaluate(expression: &str, defines: &Defines, unknown: UnknownMacros) -> Option<Value> {
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        defines,
+        unknown,
+        depth: 0,
+    };
+    let value = parser.conditional()?;
+    if parser.pos != parser.tokens.len() {
+        return None;
+    }
+    Some(value)
+}
+
+/// Tokens of an #if expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Punct(&'static str),
+}
+
+/// Operators of #if expressions, longest first so that "<<" wins over "<"
+static PUNCTUATORS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "!", "~", "+", "-", "*", "/", "%",
+    "<", ">", "&", "^", "|", "?", ":",
+];
+
+/// Splits an #if expression into tokens, None if it contains something we do not understand
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            tokens.push(Token::Number(number(&mut chars)?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        } else if c == '\'' {
+            chars.next();
+            let value = match chars.next()? {
+                '\\' => match chars.next()? {
+                    'n' => '\n' as i64,
+                    't' => '\t' as i64,
+                    '0' => 0,
+                    escaped => escaped as i64,
+                },
+                c => c as i64,
+            };
+            if chars.next()? != '\'' {
+                return None;
+            }
+            tokens.push(Token::Number(value));
+        } else {
+            let rest: String = chars.clone().take(2).collect();
+            let punct = PUNCTUATORS.iter().find(|p| rest.starts_with(**p))?;
+            for _ in 0..punct.len() {
+                chars.next();
+            }
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    Some(tokens)
+}
+
+/// Reads an integer literal (decimal, octal or hex, with an optional u/l suffix)
+fn number(chars: &mut Peekable<Chars>) -> Option<i64> {
+    let mut literal = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_alphanumeric() {
+            break;
+        }
+        literal.push(c);
+        chars.next();
+    }
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8).ok()
+    } else {
+        digits.parse().ok()
+    }
+}
+
+/// Recursive descent parser and evaluator for #if expressions.
+/// Every value is an Option, None meaning it depends on unknown macros.
+/// The outer Option of each method is None when the expression is malformed
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    defines: &'a Defines,
+    unknown: UnknownMacros,
+    /// How deep we are in macro expansions, to stop on recursive macros
+    depth: usize,
+}
+
+type Value = Option<i64>;
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        match self.peek() {
+            Some(Token::Punct(p)) if *p == punct => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// cond ? a : b
+    fn conditional(&mut self) -> Option<Value> {
+        let cond = self.binary(0)?;
+        if !self.eat("?") {
+            return Some(cond);
+        }
+        let then = self.conditional()?;
+        if !self.eat(":") {
+            return None;
+        }
+        let otherwise = self.conditional()?;
+        Some(match cond {
+            Some(0) => otherwise,
+            Some(_) => then,
+            None if then == otherwise => then,
+            None => None,
+        })
+    }
+
+    /// Binary operators, by precedence climbing
+    fn binary(&mut self, min_precedence: u8) -> Option<Value> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Punct(op)) = self.peek() {
+            let op = *op;
+            let precedence = match precedence(op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<Value> {
+        if self.eat("!") {
+            return Some(self.unary()?.map(|v| (v == 0) as i64));
+        }
+        if self.eat("~") {
+            return Some(self.unary()?.map(|v| !v));
+        }
+        if self.eat("-") {
+            return Some(self.unary()?.map(|v| v.wrapping_neg()));
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Option<Value> {
+        if self.eat("(") {
+            let value = self.conditional()?;
+            return if self.eat(")") { Some(value) } else { None };
+        }
+        let token = self.peek()?.clone();
+        self.pos += 1;
+        match token {
+            Token::Number(n) => Some(Some(n)),
+            Token::Ident(name) if name == "defined" => {
+                let parens = self.eat("(");
+                let name = match self.peek()? {
+                    Token::Ident(name) => name.clone(),
+                    _ => return None,
+                };
+                self.pos += 1;
+                if parens && !self.eat(")") {
+                    return None;
+                }
+                Some(is_defined(&name, self.defines, self.unknown).map(|d| d as i64))
+            }
+            Token::Ident(name) => {
+                if self.eat("(") {
+                    // Function-like macro call (or __has_include), which we do not expand
+                    let mut open = 1;
+                    while open > 0 {
+                        match self.peek()? {
+                            Token::Punct("(") => open += 1,
+                            Token::Punct(")") => open -= 1,
+                            _ => {}
+                        }
+                        self.pos += 1;
+                    }
+                    return Some(None);
+                }
+                Some(self.expand(&name))
+            }
+            Token::Punct(_) => None,
+        }
+    }
+
+    /// The value of an identifier in an #if expression
+    fn expand(&self, name: &str) -> Value {
+        match self.defines.get(name) {
+            None => unknown_value(self.unknown),
+            Some(Macro::Undefined) => Some(0),
+            Some(Macro::Function) | Some(Macro::Unknown) => None,
+            Some(Macro::Object(value)) => {
+                if self.depth > 16 {
+                    // Self referential macros are not expanded again, so they evaluate to 0
+                    return Some(0);
+                }
+                let mut parser = Parser {
+                    tokens: tokenize(value)?,
+                    pos: 0,
+                    defines: self.defines,
+                    unknown: self.unknown,
+                    depth: self.depth + 1,
+                };
+                let value = parser.conditional()?;
+                if parser.pos != parser.tokens.len() {
+                    return None;
+                }
+                value
+            }
+        }
+    }
+}
+
+/// Precedence of binary operators, higher binds tighter
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "*" | "/" | "%" => 10,
+        "+" | "-" => 9,
+        "<<" | ">>" => 8,
+        "<" | ">" | "<=" | ">=" => 7,
+        "==" | "!=" => 6,
+        "&" => 5,
+        "^" => 4,
+        "|" => 3,
+        "&&" => 2,
+        "||" => 1,
+        _ => return None,
+    })
+}
+
+/// Applies a binary operator. The outer None means the operation is invalid (division by 0)
+fn apply(op: &str, lhs: Value, rhs: Value) -> Option<Value> {
+    // These two can be decided with just one side known
+    match (op, lhs, rhs) {
+        ("&&", Some(0), _) | ("&&", _, Some(0)) => return Some(Some(0)),
+        ("||", Some(l), _) | ("||", _, Some(l)) if l != 0 => return Some(Some(1)),
+        _ => {}
+    }
+    let (l, r) = match (lhs, rhs) {
+        (Some(l), Some(r)) => (l, r),
+        _ => return Some(None),
+    };
+    let value = match op {
+        "*" => l.wrapping_mul(r),
+        "/" if r == 0 => return None,
+        "/" => l.wrapping_div(r),
+        "%" if r == 0 => return None,
+        "%" => l.wrapping_rem(r),
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "<<" => l.wrapping_shl(r as u32),
+        ">>" => l.wrapping_shr(r as u32),
+        "<" => (l < r) as i64,
+        ">" => (l > r) as i64,
+        "<=" => (l <= r) as i64,
+        ">=" => (l >= r) as i64,
+        "==" => (l == r) as i64,
+        "!=" => (l != r) as i64,
+        "&" => l & r,
+        "^" => l ^ r,
+        "|" => l | r,
+        "&&" => (l != 0 && r != 0) as i64,
+        "||" => (l != 0 || r != 0) as i64,
+        _ => return None,
+    };
+    Some(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The paths of the live includes, with whether they are conditional
+    fn includes(source: &str, header: bool, defines: &Defines, unknown: UnknownMacros) -> Vec<(String, bool)> {
+        preprocess(source, header, defines, unknown)
+            .includes
+            .into_iter()
+            .map(|include| (include.path, include.conditional))
+            .collect()
+    }
+
+    fn defines(args: &[&str]) -> Defines {
+        let mut defines = Defines::new();
+        args.iter().for_each(|arg| defines.define_arg(arg));
+        defines
+    }
+
+    fn live(paths: &[&str]) -> Vec<(String, bool)> {
+        paths.iter().map(|path| (path.to_string(), false)).collect()
+    }
+
+    #[test]
+    fn follows_the_branch_of_defined() {
+        let source = "#if defined(A) && !defined B\n#include \"a.h\"\n#endif\n";
+        assert_eq!(includes(source, false, &defines(&["A"]), UnknownMacros::False), live(&["a.h"]));
+        assert_eq!(includes(source, false, &defines(&["A", "B"]), UnknownMacros::False), live(&[]));
+    }
+
+    #[test]
+    fn follows_the_first_true_of_if_elif_else() {
+        let source = "#if LEVEL > 2\n#include \"high.h\"\n#elif LEVEL == 2\n#include \"mid.h\"\n#else\n#include \"low.h\"\n#endif\n";
+        let unknown = UnknownMacros::False;
+        assert_eq!(includes(source, false, &defines(&["LEVEL=3"]), unknown), live(&["high.h"]));
+        assert_eq!(includes(source, false, &defines(&["LEVEL=2"]), unknown), live(&["mid.h"]));
+        assert_eq!(includes(source, false, &defines(&["LEVEL=1"]), unknown), live(&["low.h"]));
+    }
+
+    #[test]
+    fn nested_branches_of_dead_ones_are_dead() {
+        let source = "#ifdef A\n#if 1\n#include \"a.h\"\n#endif\n#else\n#include \"b.h\"\n#endif\n";
+        assert_eq!(includes(source, false, &Defines::new(), UnknownMacros::False), live(&["b.h"]));
+    }
+
+    #[test]
+    fn tracks_the_defines_of_the_file() {
+        let source = "#define USE_A\n#ifdef USE_A\n#include \"a.h\"\n#endif\n#undef USE_A\n#ifdef USE_A\n#include \"b.h\"\n#endif\n";
+        assert_eq!(includes(source, false, &Defines::new(), UnknownMacros::True), live(&["a.h"]));
+    }
+
+    #[test]
+    fn unknown_macros_are_what_the_policy_says() {
+        let source = "#ifdef FOO\n#include \"a.h\"\n#else\n#include \"b.h\"\n#endif\n";
+        let none = Defines::new();
+        assert_eq!(includes(source, false, &none, UnknownMacros::False), live(&["b.h"]));
+        assert_eq!(includes(source, false, &none, UnknownMacros::True), live(&["a.h"]));
+        assert_eq!(
+            includes(source, false, &none, UnknownMacros::Both),
+            vec![("a.h".to_string(), true), ("b.h".to_string(), true)]
+        );
+        // Known macros are not affected
+        assert_eq!(includes(source, false, &defines(&["FOO"]), UnknownMacros::Both), live(&["a.h"]));
+    }
+
+    #[test]
+    fn a_define_in_an_unknown_branch_makes_the_macro_unknown() {
+        let source = "#ifdef FOO\n#define BAR\n#endif\n#ifdef BAR\n#include \"a.h\"\n#endif\n";
+        assert_eq!(
+            includes(source, false, &Defines::new(), UnknownMacros::Both),
+            vec![("a.h".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn the_include_guard_of_a_header_is_not_defined_yet() {
+        let source = "#ifndef UTIL_H\n#define UTIL_H\n#include \"a.h\"\n#endif\n";
+        assert_eq!(includes(source, true, &Defines::new(), UnknownMacros::True), live(&["a.h"]));
+    }
+
+    #[test]
+    fn the_include_guard_never_overrides_a_macro_given_by_the_user() {
+        let source = "#ifndef LEVEL\n#define LEVEL 1\n#endif\n#if LEVEL == 2\n#include \"two.h\"\n#endif\n";
+        assert_eq!(includes(source, true, &defines(&["LEVEL=2"]), UnknownMacros::False), live(&["two.h"]));
+        assert_eq!(includes(source, false, &defines(&["LEVEL=2"]), UnknownMacros::False), live(&["two.h"]));
+    }
+
+    #[test]
+    fn the_include_guard_is_only_for_headers() {
+        let source = "#ifndef CONFIG_X\n#define CONFIG_X\n#include \"a.h\"\n#endif\n";
+        assert_eq!(includes(source, false, &Defines::new(), UnknownMacros::True), live(&[]));
+    }
+
+    /// The paths of the includes met going through the source and the headers it includes,
+    /// in order, with whether they are conditional. Every header is included once
+    fn walked(source: &str, headers: &[(&str, &str)], defines: &Defines, unknown: UnknownMacros) -> Vec<(String, bool)> {
+        fn go(source: &str, header: bool, headers: &[(&str, &str)], defines: &mut Defines, unknown: UnknownMacros, met: &mut Vec<(String, bool)>) {
+            let directives = lexer::directives(source);
+            walk(&directives, header, defines, unknown, |include, defines| {
+                let first = !met.iter().any(|(path, _)| *path == include.path);
+                met.push((include.path.clone(), include.conditional));
+                let source = headers.iter().find(|(path, _)| *path == include.path).map(|(_, source)| *source);
+                if let Some(source) = source.filter(|_| first) {
+                    go(source, true, headers, defines, unknown, met);
+                }
+            });
+        }
+        let mut met = Vec::new();
+        go(source, false, headers, &mut defines.clone(), unknown, &mut met);
+        met
+    }
+
+    #[test]
+    fn the_macros_of_a_header_reach_what_comes_after_its_include() {
+        let config = ("config.h", "#ifndef CONFIG_H\n#define CONFIG_H\n#define USE_NET\n#endif\n");
+        let main = "#include \"config.h\"\n#ifdef USE_NET\n#include \"net.h\"\n#endif\n";
+        assert_eq!(walked(main, &[config], &Defines::new(), UnknownMacros::False), live(&["config.h", "net.h"]));
+        let too_early = "#ifdef USE_NET\n#include \"net.h\"\n#endif\n#include \"config.h\"\n";
+        assert_eq!(walked(too_early, &[config], &Defines::new(), UnknownMacros::False), live(&["config.h"]));
+    }
+
+    #[test]
+    fn the_macros_of_a_file_reach_the_headers_it_includes_next() {
+        let net = ("net.h", "#ifdef USE_TLS\n#include \"tls.h\"\n#endif\n");
+        let main = "#define USE_TLS\n#include \"net.h\"\n";
+        assert_eq!(walked(main, &[net], &Defines::new(), UnknownMacros::False), live(&["net.h", "tls.h"]));
+    }
+
+    #[test]
+    fn a_header_which_may_not_be_included_only_maybe_defines_its_macros() {
+        let win = ("win.h", "#define USE_WINSOCK\n");
+        let main = "#ifdef _WIN32\n#include \"win.h\"\n#endif\n#ifdef USE_WINSOCK\n#include \"sock.h\"\n#endif\n";
+        assert_eq!(walked(main, &[win], &Defines::new(), UnknownMacros::False), live(&[]));
+        assert_eq!(walked(main, &[win], &defines(&["_WIN32"]), UnknownMacros::False), live(&["win.h", "sock.h"]));
+        assert_eq!(
+            walked(main, &[win], &Defines::new(), UnknownMacros::Both),
+            vec![("win.h".to_string(), true), ("sock.h".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn evaluates_arithmetic_and_logic() {
+        let known = defines(&["A=3", "B=4"]);
+        assert_eq!(evaluate("A * B == 12 && (A << 1) > 5", &known, UnknownMacros::False), Some(true));
+        assert_eq!(evaluate("A > B || !defined(A)", &known, UnknownMacros::False), Some(false));
+        assert_eq!(evaluate("0x10 == 16", &known, UnknownMacros::False), Some(true));
+        assert_eq!(evaluate("C", &known, UnknownMacros::Both), None);
+    }
+}
@@ -0,0 +1,509 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::ScanCache;
+use crate::maker::{normalize, project_files};
+use crate::preprocessor::{self, Defines, UnknownMacros};
+
+/// The C keywords, which are never symbols
+static KEYWORDS: [&str; 44] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while", "_Alignas", "_Alignof", "_Atomic", "_Bool",
+    "_Complex", "_Generic", "_Imaginary", "_Noreturn", "_Static_assert", "_Thread_local",
+];
+
+/// Compiler extensions which are followed by a parenthesised argument we skip
+static EXTENSIONS: [&str; 5] = ["__attribute__", "__declspec", "__asm__", "__asm", "asm"];
+
+/// The symbols of a C file
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Symbols {
+    /// Functions and global variables with external linkage defined in the file
+    pub definitions: HashSet<String>,
+    /// Functions and variables declared but not defined in the file
+    pub declarations: HashSet<String>,
+    /// Identifiers used in function bodies and in initialisers
+    pub references: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Punct(char),
+    /// A whole { ... } group at file scope which is not a function body
+    Group,
+}
+
+/// Splits preprocessed C code (without comments or directives) into identifiers and
+/// punctuation. Literals are dropped and "->" is turned into '.', as both access members
+fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = c.to_string();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        } else if c.is_ascii_digit() {
+            // Numbers (with their suffixes and exponents) are never symbols
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+                    break;
+                }
+                chars.next();
+            }
+        } else if c == '"' || c == '\'' {
+            while let Some(d) = chars.next() {
+                if d == '\\' {
+                    chars.next();
+                } else if d == c || d == '\n' {
+                    break;
+                }
+            }
+        } else if c == '-' && chars.peek() == Some(&'>') {
+            chars.next();
+            tokens.push(Token::Punct('.'));
+        } else if !c.is_whitespace() {
+            tokens.push(Token::Punct(c));
+        }
+    }
+    tokens
+}
+
+/// Finds the symbols defined, declared and used in the given preprocessed C code.
+/// This is a heuristic rather than a C parser: locals which happen to have the name
+/// of a global are counted as references to it
+pub fn extract(code: &str) -> Symbols {
+    let tokens = tokenize(code);
+    let mut symbols = Symbols::default();
+    let mut statement: Vec<Token> = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Ident(ident) if EXTENSIONS.contains(&ident.as_str()) => {
+                i = skip_parens(&tokens, i + 1);
+                continue;
+            }
+            Token::Punct('{') if paren_depth(&statement) == 0 => {
+                let end = skip_braces(&tokens, i);
+                let function = if statement.last() == Some(&Token::Punct(')')) {
+                    function_name(&statement)
+                } else {
+                    None
+                };
+                if let Some(name) = function {
+                    if !has_ident(&statement, "static") && !has_ident(&statement, "typedef") {
+                        symbols.definitions.insert(name);
+                    }
+                    statement.clear();
+                } else {
+                    statement.push(Token::Group);
+                }
+                // Everything inside the braces is a use, except for members
+                add_references(&tokens[i..end], &mut symbols.references);
+                i = end;
+                continue;
+            }
+            Token::Punct(';') if paren_depth(&statement) == 0 => {
+                finish_statement(&statement, &mut symbols);
+                statement.clear();
+            }
+            token => statement.push(token.clone()),
+        }
+        i += 1;
+    }
+    symbols
+}
+
+/// Whether the preprocessed C code defines main, in any of its forms:
+/// "int main(void) {", "int main(int argc, char **argv) {", "main() {" or K&R style,
+/// "int main(argc, argv) int argc; char **argv; {"
+pub fn defines_main(code: &str) -> bool {
+    let tokens = tokenize(code);
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('{') => depth += 1,
+            Token::Punct('}') => depth -= 1,
+            Token::Ident(ident) if depth == 0 && ident == "main" => {
+                let after = skip_parens(&tokens, i + 1);
+                if after > i + 1 && starts_body(&tokens[after..]) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether the tokens after the parameters of a function start its body,
+/// maybe after the declarations of K&R parameters
+fn starts_body(tokens: &[Token]) -> bool {
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('{') => return i == 0 || tokens[i - 1] == Token::Punct(';'),
+            Token::Ident(_) | Token::Punct('*' | ',' | ';' | '[' | ']') => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Index right after the parenthesised group starting at i (if there is one)
+fn skip_parens(tokens: &[Token], i: usize) -> usize {
+    if tokens.get(i) != Some(&Token::Punct('(')) {
+        return i;
+    }
+    let mut depth = 0;
+    for (j, token) in tokens.iter().enumerate().skip(i) {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return j + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// Index right after the { ... } group starting at i
+fn skip_braces(tokens: &[Token], i: usize) -> usize {
+    let mut depth = 0;
+    for (j, token) in tokens.iter().enumerate().skip(i) {
+        match token {
+            Token::Punct('{') => depth += 1,
+            Token::Punct('}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return j + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// How many parentheses are open at the end of the tokens
+fn paren_depth(tokens: &[Token]) -> i32 {
+    tokens.iter().fold(0, |depth, token| match token {
+        Token::Punct('(') => depth + 1,
+        Token::Punct(')') => depth - 1,
+        _ => depth,
+    })
+}
+
+fn has_ident(tokens: &[Token], name: &str) -> bool {
+    tokens
+        .iter()
+        .any(|token| matches!(token, Token::Ident(ident) if ident == name))
+}
+
+/// Adds the identifiers of the tokens which are not keywords or member names
+fn add_references(tokens: &[Token], references: &mut HashSet<String>) {
+    let mut previous = None;
+    for token in tokens {
+        if let Token::Ident(ident) = token {
+            if previous != Some(&Token::Punct('.')) && !KEYWORDS.contains(&ident.as_str()) {
+                references.insert(ident.clone());
+            }
+        }
+        previous = Some(token);
+    }
+}
+
+/// The name of the function declared by the declarator, if it declares a function:
+/// the identifier right before the first parenthesis, unless that parenthesis
+/// opens a pointer declarator like in "int (*handler)(int)"
+fn function_name(declarator: &[Token]) -> Option<String> {
+    let open = declarator.iter().position(|token| *token == Token::Punct('('))?;
+    if declarator.get(open + 1) == Some(&Token::Punct('*')) {
+        return None;
+    }
+    match declarator.get(open.checked_sub(1)?)? {
+        Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// The name of the variable declared by the declarator
+/// "struct point { int x; } origin" declares origin, "int *values[4]" declares values
+/// and "int (*handler)(int)" declares handler
+fn variable_name(declarator: &[Token]) -> Option<String> {
+    // Only what follows a struct or union body can be a declarator
+    let start = declarator
+        .iter()
+        .rposition(|token| *token == Token::Group)
+        .map_or(0, |group| group + 1);
+    let declarator = &declarator[start..];
+    if let Some(open) = declarator.iter().position(|token| *token == Token::Punct('(')) {
+        // Pointer declarator, the name is inside the parentheses
+        return declarator[open..].iter().find_map(|token| match token {
+            Token::Ident(name) => Some(name.clone()),
+            _ => None,
+        });
+    }
+    let end = declarator
+        .iter()
+        .position(|token| *token == Token::Punct('['))
+        .unwrap_or(declarator.len());
+    let position = declarator[..end].iter().rposition(|token| match token {
+        Token::Ident(name) => !KEYWORDS.contains(&name.as_str()),
+        _ => false,
+    })?;
+    match position.checked_sub(1).map(|previous| &declarator[previous]) {
+        // "struct point;" only declares the tag
+        Some(Token::Ident(tag)) if tag == "struct" || tag == "union" || tag == "enum" => None,
+        _ => match &declarator[position] {
+            Token::Ident(name) => Some(name.clone()),
+            _ => None,
+        },
+    }
+}
+
+/// Records what a file scope declaration ending with ';' declares or defines
+fn finish_statement(statement: &[Token], symbols: &mut Symbols) {
+    if statement.is_empty() || has_ident(statement, "typedef") {
+        return;
+    }
+    let is_static = has_ident(statement, "static");
+    let is_extern = has_ident(statement, "extern");
+
+    // Split into the declarators, "int a = 1, b" has two
+    let mut depth = 0;
+    let declarators = statement.split(|token| {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => depth -= 1,
+            _ => {}
+        }
+        depth == 0 && *token == Token::Punct(',')
+    });
+
+    for declarator in declarators {
+        let (declarator, initializer) = match declarator.iter().position(|t| *t == Token::Punct('=')) {
+            Some(eq) => (&declarator[..eq], &declarator[eq + 1..]),
+            None => (declarator, &[][..]),
+        };
+        add_references(initializer, &mut symbols.references);
+        if is_static {
+            continue;
+        }
+        if let Some(name) = function_name(declarator) {
+            symbols.declarations.insert(name);
+        } else if let Some(name) = variable_name(declarator) {
+            if is_extern {
+                symbols.declarations.insert(name);
+            } else {
+                symbols.definitions.insert(name);
+            }
+        }
+    }
+}
+
+/// The files an executable is linked from, found through its symbols
+#[derive(Debug, Default)]
+pub struct LinkSet {
+    /// The C files to link, without the entrypoint itself
+    pub sources: Vec<String>,
+    /// Symbols declared by the project but defined nowhere
+    pub unresolved: Vec<String>,
+    /// Symbols defined by several files, with all of them. The first one is linked
+    pub ambiguous: Vec<(String, Vec<String>)>,
+}
+
+/// The symbols of all the C files of a project
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    /// Symbols of every C file, by normalised path
+    files: HashMap<String, Symbols>,
+    /// Files defining each symbol
+    definers: HashMap<String, Vec<String>>,
+    /// Symbols declared in the project's headers
+    declared: HashSet<String>,
+}
+
+impl SymbolIndex {
+    /// Scans all the C files and headers under root, taking the symbols of the unchanged files from the cache
+    pub fn build(root: &str, defines: &Defines, unknown: UnknownMacros, cache: Option<&ScanCache>) -> Self {
+        let mut index = SymbolIndex::default();
+        let extract_from = |path: &String, contents: &str| {
+            extract(&preprocessor::preprocess(contents, path.ends_with(".h"), defines, unknown).code)
+        };
+        let symbols_of = |path: &String| match cache {
+            Some(cache) => cache.symbols(path, |contents| extract_from(path, contents)).unwrap_or_default(),
+            None => extract_from(path, &fs::read_to_string(path).unwrap_or_default()),
+        };
+        for header in project_files(root, ".h") {
+            index.declared.extend(symbols_of(&header).declarations);
+        }
+        for source in project_files(root, ".c") {
+            let symbols = symbols_of(&source);
+            symbols.definitions.iter().for_each(|symbol| {
+                index
+                    .definers
+                    .entry(symbol.clone())
+                    .or_default()
+                    .push(source.clone());
+            });
+            index.files.insert(source, symbols);
+        }
+        index.definers.values_mut().for_each(|files| files.sort());
+        index
+    }
+
+    /// The transitive closure of the files needed to define the symbols used by entry.
+    /// Files defining main are never linked in, they are the entrypoints of other executables
+    pub fn link_set(&self, entry: &str, defines: &Defines, unknown: UnknownMacros) -> LinkSet {
+        let entry = normalize(entry);
+        let entry_symbols = match self.files.get(&entry) {
+            Some(symbols) => symbols.clone(),
+            None => {
+                let contents = fs::read_to_string(&entry).unwrap_or_default();
+                extract(&preprocessor::preprocess(&contents, false, defines, unknown).code)
+            }
+        };
+
+        let mut linked: BTreeSet<String> = BTreeSet::new();
+        let mut unresolved: BTreeSet<String> = BTreeSet::new();
+        let mut ambiguous: Vec<(String, Vec<String>)> = Vec::new();
+        let mut queue = vec![(entry.clone(), entry_symbols)];
+        linked.insert(entry.clone());
+
+        while let Some((_, symbols)) = queue.pop() {
+            let mut references: Vec<&String> = symbols.references.iter().collect();
+            references.sort();
+            for reference in references {
+                if symbols.definitions.contains(reference) {
+                    continue;
+                }
+                let definers: Vec<&String> = self
+                    .definers
+                    .get(reference)
+                    .map(|files| {
+                        files
+                            .iter()
+                            .filter(|file| *file == &entry || !self.defines(file, "main"))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if definers.is_empty() {
+                    if self.declared.contains(reference) || symbols.declarations.contains(reference) {
+                        unresolved.insert(reference.clone());
+                    }
+                    continue;
+                }
+                if definers.iter().any(|file| linked.contains(*file)) {
+                    continue;
+                }
+                if definers.len() > 1 {
+                    ambiguous.push((reference.clone(), definers.iter().map(|file| file.to_string()).collect()));
+                }
+                let definer = definers[0].clone();
+                linked.insert(definer.clone());
+                queue.push((definer.clone(), self.files[&definer].clone()));
+            }
+        }
+
+        linked.remove(&entry);
+        LinkSet {
+            sources: linked.into_iter().collect(),
+            unresolved: unresolved.into_iter().collect(),
+            ambiguous,
+        }
+    }
+
+    fn defines(&self, file: &str, symbol: &str) -> bool {
+        self.files
+            .get(file)
+            .is_some_and(|symbols| symbols.definitions.contains(symbol))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(set: &HashSet<String>) -> Vec<&str> {
+        let mut names: Vec<&str> = set.iter().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn finds_definitions_and_declarations() {
+        let code = "int counter = 0;\nextern int limit;\nint add(int a, int b);\nint sub(int a, int b) { return a - b; }\n";
+        let symbols = extract(code);
+        assert_eq!(names(&symbols.definitions), vec!["counter", "sub"]);
+        assert_eq!(names(&symbols.declarations), vec!["add", "limit"]);
+    }
+
+    #[test]
+    fn static_and_typedef_are_not_symbols() {
+        let code = "static int hidden;\nstatic void helper(void) {}\ntypedef int number;\ntypedef struct point { int x; } point;\n";
+        let symbols = extract(code);
+        assert!(symbols.definitions.is_empty());
+        assert!(symbols.declarations.is_empty());
+    }
+
+    #[test]
+    fn finds_the_references_in_bodies_and_initialisers() {
+        let code = "int *first = &table[0];\nvoid run(void) { struct s v; v.member = compute(limit); }\n";
+        let references = extract(code).references;
+        assert!(["table", "compute", "limit"].iter().all(|name| references.contains(*name)));
+        assert!(!references.contains("member"));
+        assert!(!references.contains("struct"));
+    }
+
+    #[test]
+    fn skips_compiler_extensions() {
+        let code = "__attribute__((noreturn)) void fail(void) { abort(); }\n";
+        assert_eq!(names(&extract(code).definitions), vec!["fail"]);
+    }
+
+    #[test]
+    fn finds_main_in_every_form() {
+        assert!(defines_main("int main(void) { return 0; }"));
+        assert!(defines_main("int main(int argc, char **argv)\n{\n}"));
+        assert!(defines_main("main() {}"));
+        assert!(defines_main("int main(argc, argv) int argc; char **argv; { return 0; }"));
+        assert!(!defines_main("int main(void);"));
+        assert!(!defines_main("void run(void) { main(); }"));
+    }
+
+    #[test]
+    fn every_file_defining_a_symbol_is_told_when_several_do() {
+        let root = std::env::temp_dir().join(format!("nofile-{}-ambiguous", std::process::id()));
+        let files = [
+            ("main.c", "void log_line(void);\nint main(void) { log_line(); return 0; }\n"),
+            ("a/log.c", "void log_line(void) {}\n"),
+            ("b/log.c", "void log_line(void) {}\n"),
+        ];
+        files.iter().for_each(|(file, contents)| {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), contents).unwrap();
+        });
+        let root = root.to_string_lossy().into_owned();
+        let index = SymbolIndex::build(&root, &Defines::new(), UnknownMacros::False, None);
+        let link_set = index.link_set(&format!("{}/main.c", root), &Defines::new(), UnknownMacros::False);
+        let definers = vec![format!("{}/a/log.c", root), format!("{}/b/log.c", root)];
+        assert_eq!(link_set.sources, definers[..1].to_vec());
+        assert_eq!(link_set.ambiguous, vec![("log_line".to_string(), definers)]);
+    }
+}
@@ -0,0 +1,359 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::utils::errors::{Diagnostic, NFError, Severity};
+
+/// Every kind of problem nofile reports, with what it means
+pub static RULES: [(&str, &str); 18] = [
+    ("unreadable-file", "A file of the project cannot be read"),
+    ("not-c-or-h", "An include names a file which is neither a .c nor a .h file"),
+    ("missing-include", "A \"quoted\" include is found nowhere"),
+    ("missing-implementation", "A C file given as the implementation of a header does not exist"),
+    ("include-cycle", "Headers include each other in a cycle"),
+    ("undefined-symbols", "An executable uses symbols which are declared but never defined"),
+    ("ambiguous-definition", "An executable uses a symbol which several C files define"),
+    ("empty-library", "A library of the configuration has no C files"),
+    ("cache-not-saved", "The scan cache cannot be saved"),
+    ("explain-symbols", "explain follows the includes and does not look at the symbols"),
+    ("not-enough-arguments", "Not enough arguments were given"),
+    ("io-error", "A file cannot be read or written"),
+    ("invalid-file-extension", "A file given is neither a .c nor a .h file"),
+    ("invalid-option", "An option is unknown or has a wrong value"),
+    ("missing-value", "An option is given without its value"),
+    ("invalid-config", "The configuration file is not valid"),
+    ("file-exists", "A file which would be overwritten already exists"),
+    ("check-failed", "check found problems in the project"),
+];
+
+/// How the warnings and the errors are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// In color, for people
+    Human,
+    /// One JSON object per line
+    Json,
+    /// A SARIF 2.1.0 log, for code scanning
+    Sarif,
+}
+
+impl DiagnosticsFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(DiagnosticsFormat::Human),
+            "json" => Some(DiagnosticsFormat::Json),
+            "sarif" => Some(DiagnosticsFormat::Sarif),
+            _ => None,
+        }
+    }
+}
+
+/// Where the diagnostics go
+trait Sink: Send {
+    fn emit(&mut self, diagnostic: &Diagnostic);
+
+    /// Whether it prints for people
+    fn is_human(&self) -> bool {
+        false
+    }
+
+    /// Called once nofile is done
+    fn finish(&mut self) {}
+}
+
+struct Human;
+
+impl Sink for Human {
+    fn emit(&mut self, diagnostic: &Diagnostic) {
+        diagnostic.print();
+    }
+
+    fn is_human(&self) -> bool {
+        true
+    }
+}
+
+/// Writes every diagnostic as soon as it is reported
+struct JsonLines {
+    out: Box<dyn Write + Send>,
+}
+
+impl Sink for JsonLines {
+    fn emit(&mut self, diagnostic: &Diagnostic) {
+        if let Ok(line) = serde_json::to_string(diagnostic) {
+            let _ = writeln!(self.out, "{}", line);
+        }
+    }
+}
+
+/// Keeps the diagnostics until the end, the log being a single JSON document
+struct Sarif {
+    out: Box<dyn Write + Send>,
+    results: Vec<Diagnostic>,
+}
+
+#[derive(Serialize)]
+struct SarifLog {
+    version: &'static str,
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    runs: Vec<SarifRun>,
+}
+
+#[derive(Serialize)]
+struct SarifRun {
+    tool: SarifTool,
+    results: Vec<SarifResult>,
+}
+
+#[derive(Serialize)]
+struct SarifTool {
+    driver: SarifDriver,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifDriver {
+    name: &'static str,
+    version: &'static str,
+    rules: Vec<SarifRule>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifRule {
+    id: &'static str,
+    short_description: SarifText,
+}
+
+#[derive(Serialize)]
+struct SarifText {
+    text: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: &'static str,
+    /// The index of the rule in the rules of the driver
+    rule_index: usize,
+    level: Severity,
+    message: SarifText,
+    locations: Vec<SarifLocation>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifLocation {
+    physical_location: SarifPhysicalLocation,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifPhysicalLocation {
+    artifact_location: SarifArtifact,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<SarifRegion>,
+}
+
+#[derive(Serialize)]
+struct SarifArtifact {
+    uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifRegion {
+    start_line: usize,
+}
+
+impl Sarif {
+    /// The log of the diagnostics, listing only the rules they use
+    fn log(&self) -> SarifLog {
+        let mut rules: Vec<&'static str> = Vec::new();
+        let results = self
+            .results
+            .iter()
+            .map(|diagnostic| {
+                let rule_index = rules.iter().position(|rule| *rule == diagnostic.rule).unwrap_or_else(|| {
+                    rules.push(diagnostic.rule);
+                    rules.len() - 1
+                });
+                // SARIF messages have no notes
+                let mut text = diagnostic.message.clone();
+                diagnostic.notes.iter().for_each(|note| text.push_str(format!("\n{}", note).as_str()));
+                let locations = diagnostic
+                    .file
+                    .iter()
+                    .map(|file| SarifLocation {
+                        physical_location: SarifPhysicalLocation {
+                            artifact_location: SarifArtifact { uri: file.clone() },
+                            region: diagnostic.line.map(|line| SarifRegion { start_line: line }),
+                        },
+                    })
+                    .collect();
+                SarifResult {
+                    rule_id: diagnostic.rule,
+                    rule_index,
+                    level: diagnostic.severity,
+                    message: SarifText { text },
+                    locations,
+                }
+            })
+            .collect();
+        let rules = rules
+            .into_iter()
+            .map(|id| SarifRule {
+                id,
+                short_description: SarifText {
+                    text: RULES.iter().find(|(rule, _)| *rule == id).map(|(_, text)| *text).unwrap_or(id).to_string(),
+                },
+            })
+            .collect();
+        SarifLog {
+            version: "2.1.0",
+            schema: "https://json.schemastore.org/sarif-2.1.0.json",
+            runs: vec![SarifRun {
+                tool: SarifTool {
+                    driver: SarifDriver {
+                        name: "nofile",
+                        version: env!("CARGO_PKG_VERSION"),
+                        rules,
+                    },
+                },
+                results,
+            }],
+        }
+    }
+}
+
+impl Sink for Sarif {
+    fn emit(&mut self, diagnostic: &Diagnostic) {
+        self.results.push(diagnostic.clone());
+    }
+
+    fn finish(&mut self) {
+        if let Ok(log) = serde_json::to_string_pretty(&self.log()) {
+            let _ = writeln!(self.out, "{}", log);
+        }
+    }
+}
+
+/// Where the diagnostics go for the whole run, in color to the standard error until set_sink
+static SINK: Mutex<Option<Box<dyn Sink>>> = Mutex::new(None);
+
+/// Reports the diagnostics in the given format: for people to the standard error,
+/// or to the file (the standard error without one) in the other formats
+pub fn set_sink(format: DiagnosticsFormat, output: Option<&str>) -> Result<(), NFError> {
+    let out = || -> Result<Box<dyn Write + Send>, NFError> {
+        match output {
+            Some(path) => Ok(Box::new(File::create(path).map_err(|e| NFError::IOError(e, path.to_string()))?)),
+            None => Ok(Box::new(io::stderr())),
+        }
+    };
+    let sink: Box<dyn Sink> = match format {
+        DiagnosticsFormat::Human => Box::new(Human),
+        DiagnosticsFormat::Json => Box::new(JsonLines { out: out()? }),
+        DiagnosticsFormat::Sarif => Box::new(Sarif {
+            out: out()?,
+            results: Vec::new(),
+        }),
+    };
+    *SINK.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+/// Whether the diagnostics are printed for people
+pub fn is_human() -> bool {
+    SINK.lock().unwrap().as_ref().is_none_or(|sink| sink.is_human())
+}
+
+pub fn report(diagnostic: &Diagnostic) {
+    match SINK.lock().unwrap().as_mut() {
+        Some(sink) => sink.emit(diagnostic),
+        None => diagnostic.print(),
+    }
+}
+
+/// Writes what the sink kept until the end
+pub fn finish() {
+    if let Some(sink) = SINK.lock().unwrap().as_mut() {
+        sink.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle() -> Diagnostic {
+        Diagnostic::warning("include-cycle", "includes itself".to_string())
+            .at("a.h", Some(3))
+            .note("a.h:3 includes a.h".to_string())
+    }
+
+    #[test]
+    fn every_rule_is_listed_once() {
+        let mut rules: Vec<&str> = RULES.iter().map(|(rule, _)| *rule).collect();
+        rules.sort_unstable();
+        rules.dedup();
+        assert_eq!(rules.len(), RULES.len());
+    }
+
+    #[test]
+    fn a_json_line_has_every_field() {
+        let line: serde_json::Value = serde_json::from_str(&serde_json::to_string(&cycle()).unwrap()).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "severity": "warning",
+                "rule": "include-cycle",
+                "message": "includes itself",
+                "file": "a.h",
+                "line": 3,
+                "notes": ["a.h:3 includes a.h"],
+            })
+        );
+    }
+
+    #[test]
+    fn the_sarif_log_lists_the_rules_it_uses() {
+        let sarif = Sarif {
+            out: Box::new(io::sink()),
+            results: vec![
+                cycle(),
+                Diagnostic::error("invalid-option", "unknown option --frobnicate".to_string()),
+                cycle().at("b.h", None),
+            ],
+        };
+        let log = serde_json::to_value(sarif.log()).unwrap();
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "nofile");
+        assert_eq!(
+            run["tool"]["driver"]["rules"],
+            serde_json::json!([
+                {"id": "include-cycle", "shortDescription": {"text": "Headers include each other in a cycle"}},
+                {"id": "invalid-option", "shortDescription": {"text": "An option is unknown or has a wrong value"}},
+            ])
+        );
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(
+            results[0],
+            serde_json::json!({
+                "ruleId": "include-cycle",
+                "ruleIndex": 0,
+                "level": "warning",
+                "message": {"text": "includes itself\na.h:3 includes a.h"},
+                "locations": [{"physicalLocation": {"artifactLocation": {"uri": "a.h"}, "region": {"startLine": 3}}}],
+            })
+        );
+        assert_eq!(results[1]["ruleIndex"], 1);
+        assert_eq!(results[1]["level"], "error");
+        assert_eq!(results[1]["locations"], serde_json::json!([]));
+        assert_eq!(results[2]["ruleIndex"], 0);
+        assert_eq!(results[2]["locations"][0]["physicalLocation"], serde_json::json!({"artifactLocation": {"uri": "b.h"}}));
+    }
+}
@@ -1,52 +1,252 @@
-use std::process;
+use serde::Serialize;
+use termion::*;
 
-#[macro_use]
-use crate::utils::utilities::*;
+use crate::utils::diagnostics;
+
+/// How bad a problem found in the project is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Reported, but nofile carries on
+    Warning,
+    /// Reported, and nofile fails once everything was looked at
+    Error,
+}
+
+/// A problem found in the project, at the file (and line) it comes from when there is one
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What kind of problem it is, one of diagnostics::RULES
+    pub rule: &'static str,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    /// More lines explaining the problem
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, rule: &'static str, message: String) -> Self {
+        Diagnostic {
+            severity,
+            rule,
+            message,
+            file: None,
+            line: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(rule: &'static str, message: String) -> Self {
+        Diagnostic::new(Severity::Warning, rule, message)
+    }
+
+    pub fn error(rule: &'static str, message: String) -> Self {
+        Diagnostic::new(Severity::Error, rule, message)
+    }
+
+    pub fn at(mut self, file: &str, line: Option<usize>) -> Self {
+        self.file = Some(file.to_string());
+        self.line = line;
+        self
+    }
+
+    pub fn note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// file:line, or just the file
+    pub fn location(&self) -> Option<String> {
+        let file = self.file.as_ref()?;
+        Some(match self.line {
+            Some(line) => format!("{}:{}", file, line),
+            None => file.clone(),
+        })
+    }
+
+    /// Prints it for people, in color
+    pub fn print(&self) {
+        let message = match self.location() {
+            Some(location) => format!("{}: {}", location, self.message),
+            None => self.message.clone(),
+        };
+        match self.severity {
+            Severity::Warning => {
+                print_yellow!["Warning: {}", message];
+                self.notes.iter().for_each(|note| {
+                    print_yellow!["    {}", note];
+                });
+            }
+            Severity::Error => {
+                print_red!["Error: {}", message];
+                self.notes.iter().for_each(|note| {
+                    print_red!["    {}", note];
+                });
+            }
+        }
+    }
+}
 
-use termion::*;
 pub enum NFError {
     NotEnoughArgs,
     IOError(std::io::Error, String),
     InvalidFileExt(String),
-}
-
-// macro_rules! color_print {
-//     (
-// }
-
-macro_rules! red {
-    () => {
-        color::Fg(color::Red)
-    };
+    InvalidOption(String),
+    MissingValue(String),
+    InvalidConfig(String, String),
+    FileExists(String),
+    /// Every problem the scan found, at least one of them an error
+    ScanFailed(Vec<Diagnostic>),
+    /// How many problems check found
+    CheckFailed(usize),
 }
 
 use NFError::*;
 impl NFError {
+    /// Reports what went wrong, the way --diagnostics says. Exiting (with exit_code) is left to main
     pub fn diagnostic(&self) {
+        if let ScanFailed(problems) = self {
+            problems.iter().for_each(diagnostics::report);
+        }
+        if !diagnostics::is_human() {
+            if let Some(rule) = self.rule() {
+                diagnostics::report(&Diagnostic::error(rule, self.message()));
+            }
+            return;
+        }
         match self {
             NotEnoughArgs => {
                 color_print![
-                    color![(color::Red)],
+                    color![color::Red],
                     "You have not given me enough argmuents, please check the spec"
                 ];
-                print_red!["------------ EXITING -----------"];
-                process::exit(1);
             }
             IOError(e, path) => {
                 print_red!["{} <- for file {}", e, path];
                 print_red!["Aborting, please rerun"];
-                print_red!["------------ EXITING -----------"];
-                process::exit(1);
             }
             InvalidFileExt(file) => {
                 color_print![
-                    color![(color::Red)],
+                    color![color::Red],
                     "You have given me a path to a file that does not contain a .c or .h extension: which is {}",
                     file
                 ];
-                println!("{}------------ EXITING -----------", red![]);
-                process::exit(1);
             }
+            InvalidOption(option) => {
+                print_red!["I do not know what to do with the option {}", option];
+            }
+            InvalidConfig(path, e) => {
+                print_red!["The configuration file {} is not valid: {}", path, e];
+            }
+            FileExists(path) => {
+                print_red!["{} already exists, I will not overwrite it", path];
+            }
+            ScanFailed(problems) => {
+                let errors = problems.iter().filter(|problem| problem.is_error()).count();
+                print_red!["{} errors found while scanning", errors];
+            }
+            CheckFailed(problems) => {
+                print_red!["{} problems found", problems];
+            }
+            MissingValue(option) => {
+                print_red!["The option {} needs a value", option];
+            }
+        }
+        print_red!["------------ EXITING -----------"];
+    }
+
+    /// The rule of the diagnostic reporting the error, None when the diagnostics are already
+    /// reported one by one
+    fn rule(&self) -> Option<&'static str> {
+        match self {
+            NotEnoughArgs => Some("not-enough-arguments"),
+            IOError(_, _) => Some("io-error"),
+            InvalidFileExt(_) => Some("invalid-file-extension"),
+            InvalidOption(_) => Some("invalid-option"),
+            MissingValue(_) => Some("missing-value"),
+            InvalidConfig(_, _) => Some("invalid-config"),
+            FileExists(_) => Some("file-exists"),
+            ScanFailed(_) => None,
+            CheckFailed(_) => Some("check-failed"),
+        }
+    }
+
+    /// What went wrong, in one line
+    fn message(&self) -> String {
+        match self {
+            NotEnoughArgs => "not enough arguments".to_string(),
+            IOError(e, path) => format!("{}: {}", path, e),
+            InvalidFileExt(file) => format!("{} is neither a .c nor a .h file", file),
+            InvalidOption(option) => format!("unknown option {}", option),
+            MissingValue(option) => format!("the option {} needs a value", option),
+            InvalidConfig(path, e) => format!("{} is not valid: {}", path, e),
+            FileExists(path) => format!("{} already exists", path),
+            ScanFailed(problems) => format!("{} problems found while scanning", problems.len()),
+            CheckFailed(problems) => format!("{} problems found", problems),
+        }
+    }
+
+    /// What nofile exits with: 1 when check found problems, 2 for a bad command line,
+    /// 3 for a bad configuration file, 4 when a file could not be read or written
+    /// and 5 when the scan found errors in the project
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CheckFailed(_) => 1,
+            NotEnoughArgs | InvalidFileExt(_) | InvalidOption(_) | MissingValue(_) => 2,
+            InvalidConfig(_, _) => 3,
+            IOError(_, _) | FileExists(_) => 4,
+            ScanFailed(_) => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_located_at_their_file_and_line() {
+        let problem = Diagnostic::warning("include-cycle", "includes itself".to_string());
+        assert_eq!(problem.location(), None);
+        assert_eq!(problem.clone().at("a.h", Some(3)).location(), Some("a.h:3".to_string()));
+        assert_eq!(problem.at("a.h", None).location(), Some("a.h".to_string()));
+        assert!(Diagnostic::error("unreadable-file", "cannot be read".to_string()).is_error());
+    }
+
+    #[test]
+    fn every_class_of_error_has_its_exit_code() {
+        let io = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(CheckFailed(2).exit_code(), 1);
+        assert_eq!(InvalidOption("--frobnicate".to_string()).exit_code(), 2);
+        assert_eq!(MissingValue("-o".to_string()).exit_code(), 2);
+        assert_eq!(InvalidConfig("nofile.toml".to_string(), String::new()).exit_code(), 3);
+        assert_eq!(IOError(io(), "main.c".to_string()).exit_code(), 4);
+        assert_eq!(ScanFailed(Vec::new()).exit_code(), 5);
+    }
+
+    #[test]
+    fn every_error_is_reported_with_a_known_rule() {
+        let io = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        let errors = [
+            NotEnoughArgs,
+            IOError(io(), "main.c".to_string()),
+            InvalidFileExt("main.txt".to_string()),
+            InvalidOption("--frobnicate".to_string()),
+            MissingValue("-o".to_string()),
+            InvalidConfig("nofile.toml".to_string(), String::new()),
+            FileExists("nofile.toml".to_string()),
+            CheckFailed(1),
+        ];
+        for error in errors.iter() {
+            let rule = error.rule().unwrap();
+            assert!(diagnostics::RULES.iter().any(|(known, _)| *known == rule), "{} is not a rule", rule);
         }
+        assert_eq!(ScanFailed(Vec::new()).rule(), None);
     }
 }
@@ -1,4 +1,6 @@
 #[macro_use]
 pub mod utilities;
 
+pub mod diagnostics;
 pub mod errors;
+pub mod pool;
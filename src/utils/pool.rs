@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// How many workers scan the files when -j is not given: one per core
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|jobs| jobs.get()).unwrap_or(1)
+}
+
+/// The tasks waiting for a worker, with how many tasks are not done yet (waiting or being done)
+struct Tasks<T> {
+    waiting: VecDeque<T>,
+    unfinished: usize,
+}
+
+/// The tasks shared by the workers. Doing a task can give new ones, which any worker picks up
+pub struct WorkQueue<T> {
+    tasks: Mutex<Tasks<T>>,
+    changed: Condvar,
+}
+
+impl<T: Send> WorkQueue<T> {
+    pub fn push(&self, task: T) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.waiting.push_back(task);
+        tasks.unfinished += 1;
+        self.changed.notify_one();
+    }
+
+    /// Waits for a task, or gives None when everything is done
+    fn next(&self) -> Option<T> {
+        let mut tasks = self.tasks.lock().unwrap();
+        loop {
+            if let Some(task) = tasks.waiting.pop_front() {
+                return Some(task);
+            }
+            if tasks.unfinished == 0 {
+                return None;
+            }
+            tasks = self.changed.wait(tasks).unwrap();
+        }
+    }
+
+    fn done(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.unfinished -= 1;
+        if tasks.unfinished == 0 {
+            // Nothing more will come: the waiting workers can stop
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Marks the task done when dropped, even when doing it panicked, so the other workers
+/// do not wait for it forever (the panic then comes out of run_tasks)
+struct Finished<'a, T: Send>(&'a WorkQueue<T>);
+
+impl<T: Send> Drop for Finished<'_, T> {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+/// Does the tasks, and the ones they push, with a fixed number of workers
+pub fn run_tasks<T: Send>(jobs: usize, tasks: Vec<T>, work: impl Fn(T, &WorkQueue<T>) + Sync) {
+    let queue = WorkQueue {
+        tasks: Mutex::new(Tasks {
+            unfinished: tasks.len(),
+            waiting: tasks.into(),
+        }),
+        changed: Condvar::new(),
+    };
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| {
+                while let Some(task) = queue.next() {
+                    let _finished = Finished(&queue);
+                    work(task, &queue);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn does_the_pushed_tasks() {
+        let done = AtomicUsize::new(0);
+        run_tasks(4, vec![3], |task: usize, queue| {
+            done.fetch_add(1, Ordering::Relaxed);
+            if task > 0 {
+                queue.push(task - 1);
+                queue.push(task - 1);
+            }
+        });
+        assert_eq!(done.load(Ordering::Relaxed), 15);
+    }
+
+    #[test]
+    fn a_panicking_task_does_not_hang_the_workers() {
+        let ran = panic::catch_unwind(|| {
+            run_tasks(2, vec![0, 1, 2, 3], |task: usize, _| {
+                if task == 1 {
+                    panic!("task {} failed", task);
+                }
+            })
+        });
+        assert!(ran.is_err());
+    }
+}
@@ -1,94 +1,210 @@
-use chashmap::{CHashMap, ReadGuard};
-use std::collections::HashSet;
-use std::ops::{Deref, DerefMut};
-use crate::maker::FilePath;
+use chashmap::CHashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::{env, fs};
+use crate::config::{ExecutableConfig, LibraryKind};
+use crate::maker::{normalize, HeaderDeps, IncludeEdge, SearchDir};
+use crate::utils::errors::Diagnostic;
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
 #[doc = "makes a color format: usage:
-``` color![(color::Red)] ```"]
+``` color![color::Red] ```"]
 macro_rules! color {
-    ($col:tt) => {
+    ($col:path) => {
         color::Fg($col)
     };
 }
 
-#[doc = "Prints to terminal with given color"]
+#[doc = "Prints to the standard error with given color, unless colors are turned off.
+The standard output is kept for what nofile generates"]
 macro_rules! color_print {
     ($color:expr, $($args:tt)*) => {
         let printed = format!($($args)*);
-        println!("{}{}", $color, printed);
+        if $crate::utils::utilities::use_color() {
+            eprintln!("{}{}{}", $color, printed, color::Fg(color::Reset));
+        } else {
+            eprintln!("{}", printed);
+        }
     }
 }
 
+#[doc = "Prints to terminal with yellow"]
 macro_rules! print_yellow {
     ($($args:tt)*) => {
-        color_print![color![(color::Yellow)], $($args)*];
+        color_print![color![color::Yellow], $($args)*];
     }
 }
 
 #[doc = "Prints to terminal with red"]
 macro_rules! print_red {
     ($($args:tt)*) => {
-        color_print![color![(color::Red)], $($args)*];
+        color_print![color![color::Red], $($args)*];
+    }
+}
+
+#[doc = "Prints to terminal with green"]
+macro_rules! print_green {
+    ($($args:tt)*) => {
+        color_print![color![color::Green], $($args)*];
     }
 }
 
 #[doc = "Prints to terminal with white"]
+#[allow(unused_macros)]
 macro_rules! print_white {
     ($($args:tt)*) => {
-        color_print![color![(color::White)], $($args)*];
+        color_print![color![color::White], $($args)*];
+    }
+
+}
+
+/// How much nofile tells about what it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only warnings and errors
+    Quiet,
+    Normal,
+    /// What was found and written as well
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+/// Sets how nofile prints, for the whole run
+pub fn set_output(verbosity: Verbosity, color: bool) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
     }
+}
 
+pub fn use_color() -> bool {
+    COLOR.load(Ordering::Relaxed)
 }
 
 static CC_IDENTIFIER: &str = "CC";
 static CFLAGS_IDENTIFIER: &str = "CFLAGS";
+static CPPFLAGS_IDENTIFIER: &str = "CPPFLAGS";
+static LDFLAGS_IDENTIFIER: &str = "LDFLAGS";
+static LDLIBS_IDENTIFIER: &str = "LDLIBS";
+static AR_IDENTIFIER: &str = "AR";
+static BUILD_DIR_IDENTIFIER: &str = "BUILD_DIR";
 // static SUFFIXES_IDENTIFIER: &str = ".SUFFIXES";
 static CLEAN_PHONY: &str = ".PHONY: all clean";
+// Objects and executables go under the build directory, never next to the sources
+pub static BUILD_DIR: &str = "build";
+// Every object is compiled once from its source, even when several executables link it
+static OBJECT_TARGET: &str = "$(BUILD_DIR)/%.o: %.c";
+static COMPILE: &str = "\t$(CC) $(CPPFLAGS) $(CFLAGS) -c $< -o $@";
+// The compiler writes the headers of each object in a .d file next to it
+static DEPFILE_COMPILE: &str = "\t$(CC) $(CPPFLAGS) $(CFLAGS) -MMD -MP -c $< -o $@";
+static MKDIR: &str = "\tmkdir -p $@";
 
 // static SUFFIXES: &str = ".c .o";
 // static CLEAN_RM: &str = "rm -f ";
 // static CLEAN_COMM: &str = "clean: ";
-static CLEAN_O: &str = "\trm -f $(wildcard *.o)";
+static CLEAN_BUILD: &str = "\trm -rf $(BUILD_DIR)";
+
+/// The path of the file inside the build directory, without the ".c":
+/// "src/foo.c" -> "src/foo". Files outside of the project directory stay inside
+/// the build directory: "../lib/foo.c" -> "__/lib/foo", "/opt/foo.c" -> "opt/foo"
+pub fn build_path(file: &str) -> String {
+    let mut path: String = normalize(file)
+        .trim_start_matches('/')
+        .split('/')
+        .map(|component| if component == ".." { "__" } else { component })
+        .collect::<Vec<&str>>()
+        .join("/");
+    ExecutableData::strip_ending(&mut path);
+    path
+}
+
+/// The object the given C file is compiled to: "src/foo.c" -> "$(BUILD_DIR)/src/foo.o"
+fn object_of(file: &str) -> String {
+    format!("$({})/{}.o", BUILD_DIR_IDENTIFIER, build_path(file))
+}
+
+/// The directory of a file in the build directory, which has to be created before it
+fn directory_of(file: &str) -> String {
+    match file.rfind('/') {
+        Some(slash) => file[..slash].to_string(),
+        None => format!("$({})", BUILD_DIR_IDENTIFIER),
+    }
+}
 
 /// The fields of a Makefile,
 /// should probably change the Strings to &'a str to save allocating a lot,
 /// but that's for the future
 #[derive(Debug)]
 pub struct Makefile {
-    c_compiler: &'static str,
+    c_compiler: String,
     //GCC by default
-    c_flags: HashSet<String>,
+    c_flags: Vec<String>,
+    /// The macros and the include search paths
+    cpp_flags: Vec<String>,
+    ld_flags: Vec<String>,
+    ld_libs: Vec<String>,
+    /// Executables with their own flags
+    executables: Vec<ExecutableConfig>,
+    /// In the order they are linked: every library comes before the ones it uses
+    libraries: Vec<Library>,
     source_files: Vec<StrPath>,
     dependencies: CHashMap<StrPath, HashSet<StrPath>>,
+    /// Dependencies only reached through #if branches on unknown macros
+    conditional_dependencies: CHashMap<StrPath, HashSet<StrPath>>,
+    /// Project headers which were found through a search path, by search path flag
+    found_headers: CHashMap<String, HashSet<String>>,
+    /// Symbols which are declared but defined nowhere, by executable
+    unresolved: CHashMap<StrPath, Vec<String>>,
+    /// How the objects are rebuilt when their headers change
+    header_deps: HeaderDeps,
+    /// The headers of every translation unit, for explicit header prerequisites
+    object_headers: Vec<(String, Vec<String>)>,
+    /// The includes between the project files reached from the executables
+    includes: Vec<IncludeEdge>,
+    /// The warnings of the scan: undefined symbols, include cycles, missing includes
+    diagnostics: Vec<Diagnostic>,
 }
 
 /// Touple struct which contains the path which will be entered in the 
 /// dependencies map. This type is necessary to compare identical files which
-/// are accessed using different paths
+/// are accessed using different paths: the second field is the identity of the file,
+/// its canonical path (symlinks resolved) or, for files which do not exist,
+/// its absolute path with "./", "../" and duplicate slashes resolved
 #[derive(Debug)]
-pub struct StrPath(String);
+pub struct StrPath(String, PathBuf);
 
 impl StrPath {
     pub fn new(path: String) -> Self {
-        Self(path)
+        let identity = StrPath::identity_of(&path);
+        Self(path, identity)
     }
 
-    fn into(self) -> String {
-        self.0
+    /// The identity of the file at path, see StrPath
+    pub fn identity_of(path: &str) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| {
+            let absolute = env::current_dir().unwrap_or_default().join(path);
+            PathBuf::from(normalize(&absolute.to_string_lossy()))
+        })
     }
 
     pub fn clone(&self) -> StrPath {
-        StrPath::new(self.0.clone())
+        StrPath(self.0.clone(), self.1.clone())
     }
 }
 
 impl Hash for StrPath {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        // Construct a hash for the StrPath ONLY according to the actual file name
-        // not the whole string
-        let path = FilePath::new(self.0.clone());
-        path.file_ref().clone().hash(state);
+        self.1.hash(state);
     }
 }
 
@@ -96,14 +212,10 @@ impl Eq for StrPath {}
 
 impl PartialEq for StrPath {
     fn eq(&self, other: &Self) -> bool {
-        let this_filepath = FilePath::new(self.0.clone());
-        let other_filepath = FilePath::new(other.0.clone());
-        let res = this_filepath.file_ref() == other_filepath.file_ref();
-        res
+        self.1 == other.1
     }
 }
 
-
 impl Deref for StrPath {
     type Target = String;
     fn deref(&self) -> &Self::Target {
@@ -111,22 +223,135 @@ impl Deref for StrPath {
     }
 }
 
-impl DerefMut for StrPath {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+/// A library made of some of the C files, which the executables using them link instead
+#[derive(Debug)]
+pub struct Library {
+    pub name: String,
+    pub kind: LibraryKind,
+    /// "MAJOR.MINOR.PATCH", for shared objects only
+    pub version: Option<String>,
+    /// Normalised paths of the C files
+    pub sources: Vec<String>,
+}
+
+impl Library {
+    pub fn contains(&self, source: &str) -> bool {
+        self.sources.contains(&normalize(source))
+    }
+
+    /// The name of the library file: "libcore.a" or "libcore.so"
+    pub fn file_name(&self) -> String {
+        match self.kind {
+            LibraryKind::Static => format!("lib{}.a", self.name),
+            LibraryKind::Shared => format!("lib{}.so", self.name),
+        }
+    }
+
+    fn file(&self) -> String {
+        format!("$({})/lib/{}", BUILD_DIR_IDENTIFIER, self.file_name())
+    }
+
+    /// The start of the names of the library's variables: "LIBCORE"
+    fn descriptor(&self) -> String {
+        format!("LIB{}", self.name)
+            .to_uppercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+
+    /// The variables and the rules of the library: its sources and objects, how it is made
+    /// (with the soname and the version symlinks of shared objects) and how its objects are compiled
+    fn format(&self) -> (String, String) {
+        let descriptor = self.descriptor();
+        let objects_descriptor = format!("{}_OBJECTS", descriptor);
+        let objects: Vec<String> = self.sources.iter().map(|source| object_of(source)).collect();
+        let variables = format!(
+            "{descriptor}_SOURCE = {sources}\n{objects_descriptor} = {objects}\n",
+            descriptor = descriptor,
+            sources = self.sources.join(" "),
+            objects_descriptor = objects_descriptor,
+            objects = objects.join(" "),
+        );
+        let mut recipe = format!(
+            "{}: $({}) | $({})/lib\n",
+            self.file(),
+            objects_descriptor,
+            BUILD_DIR_IDENTIFIER
+        );
+        match (self.kind, &self.version) {
+            (LibraryKind::Static, _) => {
+                recipe.push_str(format!("\t$({}) rcs $@ $({})\n", AR_IDENTIFIER, objects_descriptor).as_str());
+            }
+            (LibraryKind::Shared, None) => {
+                recipe.push_str(
+                    format!(
+                        "\t$(CC) $(CFLAGS) -shared -Wl,-soname,{} $(LDFLAGS) $({}) $(LDLIBS) -o $@\n",
+                        self.file_name(),
+                        objects_descriptor
+                    )
+                    .as_str(),
+                );
+            }
+            (LibraryKind::Shared, Some(version)) => {
+                // libcore.so -> libcore.so.1 (the soname) -> libcore.so.1.2.0 (the real file)
+                let major = version.split('.').next().unwrap_or(version);
+                let soname = format!("{}.{}", self.file_name(), major);
+                let real_name = format!("{}.{}", self.file_name(), version);
+                recipe.push_str(
+                    format!(
+                        "\t$(CC) $(CFLAGS) -shared -Wl,-soname,{} $(LDFLAGS) $({}) $(LDLIBS) -o $(@D)/{}\n\tln -sf {} $(@D)/{}\n\tln -sf {} $@\n",
+                        soname, objects_descriptor, real_name, real_name, soname, soname
+                    )
+                    .as_str(),
+                );
+            }
+        }
+        if self.kind == LibraryKind::Shared {
+            recipe.push_str(format!("$({}): CFLAGS += -fPIC\n", objects_descriptor).as_str());
+        }
+        (variables, recipe)
+    }
+}
+
+/// How an executable is linked: from its C files, but the ones of the libraries
+/// which are linked through the libraries
+pub struct Linking<'a> {
+    /// The entrypoint of the executable
+    pub source: String,
+    /// The C files outside of the libraries, sorted
+    pub sources: Vec<String>,
+    /// The ones only reached through #if branches on unknown macros, sorted
+    pub conditional: Vec<String>,
+    /// The libraries it uses, in the order they are linked
+    pub libraries: Vec<&'a Library>,
+    /// The linker flag finding the shared objects it uses, with the $ doubled for make and ninja
+    pub rpath: Option<String>,
+}
+
+impl Linking<'_> {
+    /// The line saying which C files are conditional, empty without any
+    pub fn conditional_comment(&self) -> String {
+        if self.conditional.is_empty() {
+            return String::new();
+        }
+        format!("# Only reached through #if branches on unknown macros: {}\n", self.conditional.join(" "))
     }
 }
 
-/// A struct which holds the Makefile data of a certain executable file Example: source: "emulate.c" exe_label: "emulate"
-/// recipe: "emulate: emulate.o\n\t$(CC) $(CFLAGS) emulate.c $(EMULATE_SOURCE) -o $@"
-/// clean_statement: "rm -f emulate"
+/// A struct which holds the Makefile data of a certain executable file Example: source: "emulate.c"
+/// exe_label: "$(BUILD_DIR)/bin/emulate"
+/// objects: "EMULATE_OBJECTS = $(BUILD_DIR)/emulate.o $(BUILD_DIR)/cpu.o"
+/// recipe: "$(BUILD_DIR)/bin/emulate: $(EMULATE_OBJECTS) | $(BUILD_DIR)/bin\n\t$(CC) $(CFLAGS) $(LDFLAGS) $(EMULATE_OBJECTS) $(LDLIBS) -o $@"
+/// overrides: "$(BUILD_DIR)/bin/emulate: LDLIBS = -lm" for the flags the executable sets itself
 struct ExecutableData {
-    source_file: String,
     source: String,
+    objects: String,
+    objects_descriptor: String,
     exe_label: String,
+    exe_dir: String,
     recipe: String,
-    clean_statement: String,
-    clean_target: String,
+    overrides: String,
 }
 
 impl ExecutableData {
@@ -138,142 +363,689 @@ impl ExecutableData {
 
     /// Constructs the Makefile data for the given executable file
     /// from the string and the entries in the makefile
-    fn from(source_file: &String, sources: HashSet<String>) -> Self {
-        let source_file = source_file.clone();
-        let mut exe_label = source_file.clone();
-        ExecutableData::strip_ending(&mut exe_label);
-        let dependencies_descriptor = format!("{}_SOURCE", exe_label).to_uppercase();
+    /// The conditional sources are linked as well, but are marked with a comment
+    fn from(linking: Linking, config: Option<&ExecutableConfig>) -> Self {
+        let conditional_comment = linking.conditional_comment();
+        let Linking {
+            source: source_file,
+            sources: dependencies,
+            conditional,
+            libraries: used,
+            rpath,
+        } = linking;
+        let mut name = source_file.clone();
+        ExecutableData::strip_ending(&mut name);
+        // Executables in different directories can have the same name, so the whole path
+        // goes into the variable name, with anything make does not like replaced
+        let descriptor: String = name
+            .to_uppercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let dependencies_descriptor = format!("{}_SOURCE", descriptor);
+        let objects_descriptor = format!("{}_OBJECTS", descriptor);
+        let exe_label = format!("$({})/bin/{}", BUILD_DIR_IDENTIFIER, build_path(&source_file));
+        let exe_dir = directory_of(&exe_label);
 
+        let mut source = conditional_comment;
+        source.push_str(format!("{} = ", dependencies_descriptor).as_str());
+        dependencies
+            .iter()
+            .chain(conditional.iter())
+            .for_each(|dep| source.push_str(format!("{} ", dep).as_str()));
+
+        // Link from the objects, so only the changed sources are compiled again
+        let mut objects = format!("{} = {}", objects_descriptor, object_of(&source_file));
+        dependencies
+            .iter()
+            .chain(conditional.iter())
+            .for_each(|dep| objects.push_str(format!(" {}", object_of(dep)).as_str()));
+        // The libraries come right after the objects, so the linker looks for what the objects need in them
+        let mut library_files = String::new();
+        let mut library_flags = String::new();
+        if !used.is_empty() {
+            used.iter().for_each(|library| library_files.push_str(format!(" {}", library.file()).as_str()));
+            library_flags.push_str(format!(" -L$({})/lib", BUILD_DIR_IDENTIFIER).as_str());
+            used.iter().for_each(|library| library_flags.push_str(format!(" -l{}", library.name).as_str()));
+        }
+        if let Some(rpath) = rpath {
+            library_flags.push_str(format!(" {}", rpath).as_str());
+        }
         let recipe = format!(
-            "{label}: {label}.o\n\t$({compiler}) $({cflags}) {start} $({dependencies}) -o $@",
+            "{label}: $({objects}){library_files} | {dir}\n\t$({compiler}) $({cflags}) $({ldflags}) $({objects}){library_flags} $({ldlibs}) -o $@",
             label = exe_label,
+            library_files = library_files,
+            library_flags = library_flags,
+            dir = exe_dir,
             compiler = CC_IDENTIFIER,
             cflags = CFLAGS_IDENTIFIER,
-            start = source_file,
-            dependencies = dependencies_descriptor,
+            ldflags = LDFLAGS_IDENTIFIER,
+            ldlibs = LDLIBS_IDENTIFIER,
+            objects = objects_descriptor,
         );
-        let clean_statement = format!("\trm -f {}", exe_label);
-        let clean_target = format!("\trm -f {}.o", exe_label);
-
-        let mut source = format!("{} = ", dependencies_descriptor);
-        let dependencies = sources;
-        dependencies
+        // Target-specific variables: the objects are compiled with the CFLAGS of the executable,
+        // which is linked with its own LDFLAGS and LDLIBS (private, so the libraries it needs do not get them)
+        let mut overrides = String::new();
+        if let Some(config) = config {
+            let objects_target = format!("$({})", objects_descriptor);
+            [
+                (&objects_target, CFLAGS_IDENTIFIER, &config.cflags),
+                (&exe_label, "private LDFLAGS", &config.ldflags),
+                (&exe_label, "private LDLIBS", &config.ldlibs),
+            ]
             .iter()
-            .for_each(|dep| source.push_str(format!("{} ", dep).as_str()));
+            .for_each(|(target, variable, flags)| {
+                if let Some(flags) = flags {
+                    overrides.push_str(format!("{}: {} = {}\n", target, variable, flags.join(" ")).as_str());
+                }
+            });
+        }
 
         Self {
-            source_file,
             source,
+            objects,
+            objects_descriptor,
             exe_label,
+            exe_dir,
             recipe,
-            clean_statement,
-            clean_target,
+            overrides,
         }
     }
 }
 
 impl Makefile {
     pub fn new(
-        c_compiler: &'static str,
-        c_flags: HashSet<String>,
+        c_compiler: String,
+        c_flags: Vec<String>,
+        cpp_flags: Vec<String>,
         source_files: Vec<StrPath>,
         dependencies: CHashMap<StrPath, HashSet<StrPath>>,
     ) -> Self {
+        let conditional_dependencies = CHashMap::new();
+        source_files.iter().for_each(|source| {
+            conditional_dependencies.insert(source.clone(), HashSet::new());
+        });
         Makefile {
             c_compiler,
             c_flags,
+            cpp_flags,
+            ld_flags: Vec::new(),
+            ld_libs: Vec::new(),
+            executables: Vec::new(),
+            libraries: Vec::new(),
             source_files,
             dependencies,
+            conditional_dependencies,
+            found_headers: CHashMap::new(),
+            unresolved: CHashMap::new(),
+            header_deps: HeaderDeps::Explicit,
+            object_headers: Vec::new(),
+            includes: Vec::new(),
+            diagnostics: Vec::new(),
         }
     }
 
-    fn ref_dependencies_for(&self, source: &String) -> ReadGuard<StrPath, HashSet<StrPath>> {
-        let source = StrPath::new(source.clone());
-        let dependencies = self.dependencies.get(&source);
-        match dependencies {
-            Some(deps) => deps,
-            None => {
-                panic!("Something went wrong, asked for the dependencies for {source}\
-                    , but {source}, wasn't inside the Map. This is an internal error. If for some reason this statement is printed, please\
-                    contact the mantainer. This IS a BUG!", source = source.0);
+    /// Sets the flags every executable is linked with, and the executables which have their own flags
+    pub fn link_with(&mut self, ld_flags: Vec<String>, ld_libs: Vec<String>, executables: Vec<ExecutableConfig>) {
+        self.ld_flags = ld_flags;
+        self.ld_libs = ld_libs;
+        self.executables = executables;
+    }
+
+    /// Sets the libraries, in the order they are linked
+    pub fn set_libraries(&mut self, libraries: Vec<Library>) {
+        self.libraries = libraries;
+    }
+
+    /// Every C file which gets compiled to an object, sorted
+    pub fn translation_units(&self) -> Vec<String> {
+        let mut units: HashSet<String> = HashSet::new();
+        self.libraries
+            .iter()
+            .for_each(|library| units.extend(library.sources.iter().cloned()));
+        self.source_files.iter().for_each(|source| {
+            units.insert(source.to_string());
+            for map in [&self.dependencies, &self.conditional_dependencies] {
+                if let Some(deps) = map.get(source) {
+                    units.extend(deps.iter().map(|dep| dep.to_string()));
+                }
             }
+        });
+        let mut units: Vec<String> = units.into_iter().collect();
+        units.sort();
+        units
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) {
+        self.diagnostics = diagnostics;
+    }
+
+    /// The warnings of the scan, by file and line
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn set_includes(&mut self, includes: Vec<IncludeEdge>) {
+        self.includes = includes;
+    }
+
+    /// Every include between the project files, in the order they were found
+    pub fn includes(&self) -> &[IncludeEdge] {
+        &self.includes
+    }
+
+    /// Sets how header changes are tracked, with the headers of every translation unit
+    /// (only needed for explicit prerequisites)
+    pub fn track_headers(&mut self, mode: HeaderDeps, object_headers: Vec<(String, Vec<String>)>) {
+        self.header_deps = mode;
+        self.object_headers = object_headers;
+    }
+
+    /// The C files linked into every executable, in the order the executables were given:
+    /// (executable source, sorted sources, sorted sources only reached through #if branches on unknown macros)
+    pub fn link_sets(&self) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let sorted = |map: &CHashMap<StrPath, HashSet<StrPath>>, source: &StrPath| {
+            let mut files: Vec<String> = map
+                .get(source)
+                .map(|deps| deps.iter().map(|dep| dep.to_string()).collect())
+                .unwrap_or_default();
+            files.sort();
+            files
+        };
+        self.source_files
+            .iter()
+            .map(|source| {
+                let sources = sorted(&self.dependencies, source);
+                let conditional = sorted(&self.conditional_dependencies, source);
+                (source.to_string(), sources, conditional)
+            })
+            .collect()
+    }
+
+    /// How every executable is linked, in the order the executables were given
+    pub fn linkings(&self) -> Vec<Linking<'_>> {
+        self.link_sets()
+            .into_iter()
+            .map(|(source, mut sources, mut conditional)| {
+                // The C files of the libraries are linked through the libraries, in the order of the libraries
+                let libraries: Vec<&Library> = self
+                    .libraries
+                    .iter()
+                    .filter(|library| sources.iter().chain(conditional.iter()).any(|file| library.contains(file)))
+                    .collect();
+                let in_library = |file: &String| self.libraries.iter().any(|library| library.contains(file));
+                sources.retain(|file| !in_library(file));
+                conditional.retain(|file| !in_library(file));
+                let rpath = libraries.iter().any(|library| library.kind == LibraryKind::Shared).then(|| {
+                    // The executable finds the shared objects next to bin/ wherever the build directory goes
+                    let up = "../".repeat(build_path(&source).split('/').count());
+                    format!("-Wl,-rpath,'$$ORIGIN/{}lib'", up)
+                });
+                Linking {
+                    source,
+                    sources,
+                    conditional,
+                    libraries,
+                    rpath,
+                }
+            })
+            .collect()
+    }
+
+    /// Records the symbols nothing defines for the given executable
+    pub fn add_unresolved(&self, source: &str, symbols: Vec<String>) {
+        if !symbols.is_empty() {
+            self.unresolved.insert(StrPath::new(source.to_string()), symbols);
+        }
+    }
+
+    /// The symbols nothing defines, by executable, in the order the executables were given
+    pub fn unresolved(&self) -> Vec<(String, Vec<String>)> {
+        self.source_files
+            .iter()
+            .filter_map(|source| {
+                let symbols = self.unresolved.get(source)?;
+                Some((source.to_string(), symbols.clone()))
+            })
+            .collect()
+    }
+
+    /// Records that the given header was found through the search path via
+    pub fn add_found_header(&self, via: &SearchDir, header: String) {
+        if let Some(flag) = via.flag() {
+            self.found_headers.upsert(
+                flag,
+                || std::iter::once(header.clone()).collect(),
+                |headers| {
+                    headers.insert(header.clone());
+                },
+            );
         }
     }
 
     /// Adds the given header dependency to the dependencies of "source", if it's not inside
-    pub fn add_dependency(&self, source: &String, dependency: String) {
-        let source = StrPath::new(source.clone());
-        let deps = self.dependencies.get_mut(&source);
-        match deps {
-            Some(mut deps) => {
-                deps.insert(StrPath::new(dependency));
+    /// A conditional dependency never overrides an unconditional one,
+    /// while an unconditional one removes the conditional mark
+    pub fn add_dependency(&self, source: &str, dependency: String, conditional: bool) {
+        let source = StrPath::new(source.to_string());
+        let dependency = StrPath::new(dependency);
+        // Always lock dependencies before conditional_dependencies, and keep it locked until
+        // both are updated: otherwise another worker can add the file in between, to both sets
+        if conditional {
+            let deps = match self.dependencies.get_mut(&source) {
+                Some(deps) => deps,
+                None => return,
+            };
+            if deps.contains(&dependency) {
+                return;
             }
-            None => {}
-        };
+            if let Some(mut conditional_deps) = self.conditional_dependencies.get_mut(&source) {
+                conditional_deps.insert(dependency);
+            }
+        } else if let Some(mut deps) = self.dependencies.get_mut(&source) {
+            if let Some(mut conditional_deps) = self.conditional_dependencies.get_mut(&source) {
+                conditional_deps.remove(&dependency);
+            }
+            deps.insert(dependency);
+        }
     }
 
-    /// Returns whether the dependencies of "source" has the given "dependency"
-    pub fn has_dependency(&self, source: &String, dependency: &String) -> bool {
-        let source = StrPath::new(source.clone());
-        let ref deps = self.dependencies.get(&source);
-        match deps {
-            Some(deps) => {
-                let res = deps.contains(&StrPath::new(dependency.clone()));
-                res
-            },
-            None => false
-        }
+    pub fn c_compiler(&self) -> &str {
+        &self.c_compiler
+    }
+
+    pub fn c_flags(&self) -> &[String] {
+        &self.c_flags
+    }
+
+    /// The -D, -U, -iquote, -I and -isystem flags, in order
+    pub fn cpp_flags(&self) -> &[String] {
+        &self.cpp_flags
+    }
+
+    pub fn ld_flags(&self) -> &[String] {
+        &self.ld_flags
+    }
+
+    pub fn ld_libs(&self) -> &[String] {
+        &self.ld_libs
+    }
+
+    /// The libraries, in the order they are linked
+    pub fn libraries(&self) -> &[Library] {
+        &self.libraries
+    }
+
+    /// The flags the configuration gives to the executable with the given entrypoint
+    pub fn executable_config(&self, source: &str) -> Option<&ExecutableConfig> {
+        let source = StrPath::new(source.to_string());
+        self.executables
+            .iter()
+            .find(|executable| StrPath::new(executable.source.clone()) == source)
+    }
+
+    /// Every translation unit, sorted, with the CFLAGS its object is compiled with: the ones of
+    /// the last executable linking it which has its own, or the ones of the project (with -fPIC
+    /// for the shared objects)
+    pub fn object_cflags(&self) -> Vec<(String, Vec<String>)> {
+        let mut own: HashMap<String, &Vec<String>> = HashMap::new();
+        self.link_sets().into_iter().for_each(|(source, sources, conditional)| {
+            if let Some(cflags) = self.executable_config(&source).and_then(|config| config.cflags.as_ref()) {
+                std::iter::once(source).chain(sources).chain(conditional).for_each(|file| {
+                    own.insert(file, cflags);
+                });
+            }
+        });
+        self.translation_units()
+            .into_iter()
+            .map(|source| {
+                let cflags = match self.libraries.iter().find(|library| library.contains(&source)) {
+                    Some(library) if library.kind == LibraryKind::Shared => {
+                        let mut cflags = self.c_flags.clone();
+                        cflags.push("-fPIC".to_string());
+                        cflags
+                    }
+                    Some(_) => self.c_flags.clone(),
+                    None => own.get(&source).map_or_else(|| self.c_flags.clone(), |cflags| cflags.to_vec()),
+                };
+                (source, cflags)
+            })
+            .collect()
+    }
+
+    /// The project headers found through each search path, sorted, by search path flag
+    pub fn found_headers(&self) -> Vec<(String, Vec<String>)> {
+        let mut found: Vec<(String, Vec<String>)> = self
+            .cpp_flags
+            .iter()
+            .filter_map(|flag| {
+                let mut headers: Vec<String> = self.found_headers.get(flag)?.iter().cloned().collect();
+                headers.sort();
+                Some((flag.clone(), headers))
+            })
+            .collect();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+        found.dedup_by(|(a, _), (b, _)| a == b);
+        found
     }
 
     /// Formats the items of the Makefile struct into
     /// the actual Makefile
     /// PRE: self.source_files are guaranteed to have a .c at the end
-    pub fn format(self) -> String {
-        let c_compiler = format!("CC = {}", self.c_compiler);
-        let c_flags = "CFLAGS = -Wall -g -pedantic -std=c99".to_string();
+    pub fn format(&self) -> String {
+        let mut c_compiler = format!("CC = {}", self.c_compiler);
+        if self.libraries.iter().any(|library| library.kind == LibraryKind::Static) {
+            c_compiler.push_str(format!("\n{} = ar", AR_IDENTIFIER).as_str());
+        }
+        // The order of the flags matters, the search paths are searched in this order
+        let mut c_flags = [
+            (CFLAGS_IDENTIFIER, &self.c_flags),
+            (CPPFLAGS_IDENTIFIER, &self.cpp_flags),
+            (LDFLAGS_IDENTIFIER, &self.ld_flags),
+            (LDLIBS_IDENTIFIER, &self.ld_libs),
+        ]
+        .iter()
+        .map(|(variable, flags)| format!("{} = {}", variable, flags.join(" ")))
+        .collect::<Vec<String>>()
+        .join("\n");
+        let build_dir = format!("{} = {}", BUILD_DIR_IDENTIFIER, BUILD_DIR);
+        let found_headers = self.found_headers();
+        if !found_headers.is_empty() {
+            c_flags.push_str("\n\n# Project headers found through the include search paths");
+        }
+        found_headers.into_iter().for_each(|(flag, headers)| {
+            c_flags.push_str(format!("\n# {}: {}", flag, headers.join(" ")).as_str());
+        });
         let suffixes = ".SUFFIXES: .c .o";
-        let phony_clean = format!("{}", CLEAN_PHONY);
+        let phony_clean = CLEAN_PHONY.to_string();
 
-        let map = self.dependencies.clear();
-        let files_data: Vec<ExecutableData> = map
+        let translation_units = self.translation_units();
+        // The executables stay in the order they were given to us
+        let files_data: Vec<ExecutableData> = self
+            .linkings()
             .into_iter()
-            .map(|(source_file, deps)| {
-                let deps = deps.into_iter().map(|strpath| strpath.into()).collect();
-                ExecutableData::from(&source_file, deps)
+            .map(|linking| {
+                let config = self.executable_config(&linking.source);
+                ExecutableData::from(linking, config)
             })
             .collect();
 
         let mut sources = String::new();
         files_data
             .iter()
-            .for_each(|data| sources.push_str(format!("{}\n", data.source).as_str()));
+            .for_each(|data| sources.push_str(format!("{}\n{}\n", data.source, data.objects).as_str()));
+        let libraries: Vec<(String, String)> = self.libraries.iter().map(|library| library.format()).collect();
+        libraries.iter().for_each(|(variables, _)| sources.push_str(variables));
         sources.push('\n');
 
         // Collect all the tags
         let mut all = String::from("all: ");
 
         let mut recipes = String::new();
+        let compile = match self.header_deps {
+            HeaderDeps::Explicit => COMPILE,
+            HeaderDeps::Depfiles => DEPFILE_COMPILE,
+        };
+        let mut object_rule = format!("{}\n{}\n", OBJECT_TARGET, compile);
+        // Every object needs its directory in the build directory, and is rebuilt
+        // when one of the headers it includes changes
+        let object_headers: HashMap<&String, &Vec<String>> =
+            self.object_headers.iter().map(|(source, headers)| (source, headers)).collect();
+        let mut directories: BTreeSet<String> = BTreeSet::new();
+        translation_units.iter().for_each(|source| {
+            let object = object_of(source);
+            let directory = directory_of(&object);
+            let mut prerequisites = String::new();
+            // Sources outside of the project directory do not match the pattern rule
+            let in_tree = normalize(source) == format!("{}.c", build_path(source));
+            if !in_tree {
+                prerequisites.push_str(format!(" {}", source).as_str());
+            }
+            if let Some(headers) = object_headers.get(source) {
+                headers.iter().for_each(|header| prerequisites.push_str(format!(" {}", header).as_str()));
+            }
+            object_rule.push_str(format!("{}:{} | {}\n", object, prerequisites, directory).as_str());
+            if !in_tree {
+                object_rule.push_str(format!("{}\n", compile).as_str());
+            }
+            directories.insert(directory);
+        });
 
         let mut clean = String::from("clean:\n");
-        clean.push_str(CLEAN_O);
+        clean.push_str(CLEAN_BUILD);
         clean.push('\n');
+        let mut depfiles = String::new();
+        if self.header_deps == HeaderDeps::Depfiles {
+            let objects: Vec<String> = files_data
+                .iter()
+                .map(|data| format!("$({})", data.objects_descriptor))
+                .chain(self.libraries.iter().map(|library| format!("$({}_OBJECTS)", library.descriptor())))
+                .collect();
+            sources.push_str(format!("OBJECTS = $(sort {})\nDEPS = $(OBJECTS:.o=.d)\n\n", objects.join(" ")).as_str());
+            depfiles.push_str("\n-include $(DEPS)\n");
+        }
 
         files_data.iter().for_each(|data| {
             all.push_str(format!("{} ", data.exe_label).as_str());
-            recipes.push_str(format!("{}\n\n", data.recipe).as_str());
-            clean.push_str(format!("{}\n{}\n", data.clean_statement, data.clean_target).as_str());
+            recipes.push_str(format!("{}\n{}\n", data.recipe, data.overrides).as_str());
+            directories.insert(data.exe_dir.clone());
+        });
+        self.libraries.iter().zip(libraries.iter()).for_each(|(library, (_, recipe))| {
+            all.push_str(format!("{} ", library.file()).as_str());
+            recipes.push_str(format!("{}\n", recipe).as_str());
+            directories.insert(format!("$({})/lib", BUILD_DIR_IDENTIFIER));
         });
+        let directories: Vec<String> = directories.into_iter().collect();
+        let mkdir = format!("{}:\n{}", directories.join(" "), MKDIR);
 
-        let makefile = format!("{cc}\n{cflags}\n\n{sources}{suffixes}\n\n{phony_clean}\n\n{all_exes}\n\n{recipes}{clean}",
+        let makefile = format!("{cc}\n{build_dir}\n{cflags}\n\n{sources}{suffixes}\n\n{phony_clean}\n\n{all_exes}\n\n{recipes}{object_rule}\n{mkdir}\n\n{clean}{depfiles}",
                                cc = c_compiler,
                                cflags = c_flags,
+                               build_dir = build_dir,
                                sources = sources,
                                suffixes = suffixes,
                                phony_clean = phony_clean,
                                all_exes = all,
                                recipes = recipes,
-                               clean = clean
+                               object_rule = object_rule,
+                               mkdir = mkdir,
+                               clean = clean,
+                               depfiles = depfiles
         );
         makefile
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ExecutableConfig, LibraryKind};
+
+    /// The Makefile of the executables, each linked from the given C files
+    fn makefile(cpp_flags: &[&str], executables: &[(&str, &[&str])]) -> Makefile {
+        let dependencies = CHashMap::new();
+        executables.iter().for_each(|(source, _)| {
+            dependencies.insert(StrPath::new(source.to_string()), HashSet::new());
+        });
+        let source_files = executables.iter().map(|(source, _)| StrPath::new(source.to_string())).collect();
+        let flags = |flags: &[&str]| flags.iter().map(|flag| flag.to_string()).collect();
+        let makefile = Makefile::new("gcc".to_string(), flags(&["-Wall", "-g"]), flags(cpp_flags), source_files, dependencies);
+        executables.iter().for_each(|(source, files)| {
+            files.iter().for_each(|file| makefile.add_dependency(source, file.to_string(), false));
+        });
+        makefile
+    }
+
+    #[test]
+    fn the_search_paths_go_to_the_cppflags_in_order() {
+        let makefile = makefile(&["-iquote quote", "-Iinc"], &[("main.c", &[])]);
+        makefile.add_found_header(&SearchDir::Include("inc".to_string()), "inc/util.h".to_string());
+        let contents = makefile.format();
+        assert!(contents.contains("CPPFLAGS = -iquote quote -Iinc\n"));
+        assert!(contents.contains("# -Iinc: inc/util.h"));
+    }
+
+    #[test]
+    fn files_with_the_same_name_in_different_directories_differ() {
+        assert_ne!(StrPath::new("net/util.c".to_string()), StrPath::new("fs/util.c".to_string()));
+    }
+
+    #[test]
+    fn different_spellings_of_a_path_are_the_same_file() {
+        let file = StrPath::new("src/util.c".to_string());
+        assert_eq!(file, StrPath::new("./src/util.c".to_string()));
+        assert_eq!(file, StrPath::new("src/../src//util.c".to_string()));
+        let here = env::current_dir().unwrap();
+        let here = here.file_name().unwrap().to_string_lossy();
+        assert_eq!(file, StrPath::new(format!("../{}/src/util.c", here)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_is_the_file_it_points_to() {
+        let dir = env::temp_dir().join(format!("nofile-{}-symlink", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("util.c"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("util.c"), dir.join("link.c")).unwrap();
+        let path = |name: &str| StrPath::new(dir.join(name).to_string_lossy().into_owned());
+        assert_eq!(path("link.c"), path("util.c"));
+        assert_ne!(path("link.c"), path("other.c"));
+    }
+
+    #[test]
+    fn executables_are_linked_from_objects_compiled_once() {
+        let contents = makefile(&[], &[("main.c", &["util.c"]), ("tools/run.c", &["util.c"])]).format();
+        assert!(contents.contains("MAIN_OBJECTS = $(BUILD_DIR)/main.o $(BUILD_DIR)/util.o\n"));
+        assert!(contents.contains("TOOLS_RUN_OBJECTS = $(BUILD_DIR)/tools/run.o $(BUILD_DIR)/util.o\n"));
+        assert!(contents.contains("$(BUILD_DIR)/bin/main: $(MAIN_OBJECTS) | $(BUILD_DIR)/bin\n\t$(CC) $(CFLAGS) $(LDFLAGS) $(MAIN_OBJECTS) $(LDLIBS) -o $@\n"));
+        assert_eq!(contents.matches("-c $< -o $@").count(), 1);
+    }
+
+    #[test]
+    fn objects_depend_on_the_headers_they_include() {
+        let mut makefile = makefile(&[], &[("main.c", &["util.c"])]);
+        makefile.track_headers(
+            HeaderDeps::Explicit,
+            vec![("main.c".to_string(), vec!["util.h".to_string(), "types.h".to_string()]), ("util.c".to_string(), vec![])],
+        );
+        let contents = makefile.format();
+        assert!(contents.contains("\n$(BUILD_DIR)/main.o: util.h types.h | $(BUILD_DIR)\n"));
+        assert!(contents.contains("\n$(BUILD_DIR)/util.o: | $(BUILD_DIR)\n"));
+        assert!(!contents.contains("-MMD"));
+    }
+
+    #[test]
+    fn the_compiler_writes_the_depfiles_the_makefile_includes() {
+        let mut makefile = makefile(&[], &[("main.c", &["util.c"])]);
+        makefile.track_headers(HeaderDeps::Depfiles, Vec::new());
+        let contents = makefile.format();
+        assert!(contents.contains("\t$(CC) $(CPPFLAGS) $(CFLAGS) -MMD -MP -c $< -o $@\n"));
+        assert!(contents.contains("DEPS = $(OBJECTS:.o=.d)\n"));
+        assert!(contents.ends_with("-include $(DEPS)\n"));
+    }
+
+    #[test]
+    fn builds_out_of_tree_under_the_build_dir() {
+        let contents = makefile(&[], &[("main.c", &["util.c"]), ("tools/run.c", &[])]).format();
+        assert!(contents.contains("BUILD_DIR = build\n"));
+        assert!(contents.contains("all: $(BUILD_DIR)/bin/main $(BUILD_DIR)/bin/tools/run \n"));
+        assert!(contents.contains("$(BUILD_DIR)/%.o: %.c\n"));
+        assert!(contents.contains("$(BUILD_DIR)/tools/run.o: | $(BUILD_DIR)/tools\n"));
+        assert!(contents.contains("\n$(BUILD_DIR) $(BUILD_DIR)/bin $(BUILD_DIR)/bin/tools $(BUILD_DIR)/tools:\n\tmkdir -p $@\n"));
+        assert!(contents.ends_with("clean:\n\trm -rf $(BUILD_DIR)\n"));
+    }
+
+    #[test]
+    fn files_outside_the_project_stay_in_the_build_dir() {
+        assert_eq!(object_of("src/./foo.c"), "$(BUILD_DIR)/src/foo.o");
+        assert_eq!(object_of("../lib/foo.c"), "$(BUILD_DIR)/__/lib/foo.o");
+        assert_eq!(object_of("/opt/foo.c"), "$(BUILD_DIR)/opt/foo.o");
+    }
+
+    #[test]
+    fn the_flags_of_an_executable_replace_the_global_ones() {
+        let mut makefile = makefile(&["-DNDEBUG"], &[("main.c", &[]), ("tools/run.c", &[])]);
+        let run = ExecutableConfig {
+            source: "tools/run.c".to_string(),
+            cflags: Some(vec!["-O2".to_string()]),
+            ldflags: None,
+            ldlibs: Some(vec!["-lpthread".to_string()]),
+        };
+        makefile.link_with(vec!["-L/opt/lib".to_string()], vec!["-lm".to_string()], vec![run]);
+        let contents = makefile.format();
+        assert!(contents.starts_with("CC = gcc\nBUILD_DIR = build\nCFLAGS = -Wall -g\nCPPFLAGS = -DNDEBUG\nLDFLAGS = -L/opt/lib\nLDLIBS = -lm\n"));
+        assert!(contents.contains("\n$(TOOLS_RUN_OBJECTS): CFLAGS = -O2\n$(BUILD_DIR)/bin/tools/run: private LDLIBS = -lpthread\n"));
+        assert!(!contents.contains("$(MAIN_OBJECTS): CFLAGS"));
+    }
+
+    /// A library of the given C files
+    fn library(name: &str, kind: LibraryKind, version: Option<&str>, sources: &[&str]) -> Library {
+        Library {
+            name: name.to_string(),
+            kind,
+            version: version.map(String::from),
+            sources: sources.iter().map(|source| source.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn executables_link_the_libraries_in_order_instead_of_their_objects() {
+        let mut makefile = makefile(&[], &[("main.c", &["net/io.c", "core/log.c"])]);
+        makefile.set_libraries(vec![
+            library("net", LibraryKind::Static, None, &["net/io.c"]),
+            library("core", LibraryKind::Shared, Some("1.2.0"), &["core/log.c"]),
+        ]);
+        let contents = makefile.format();
+        assert!(contents.contains("MAIN_OBJECTS = $(BUILD_DIR)/main.o\n"));
+        assert!(contents.contains(
+            "$(BUILD_DIR)/bin/main: $(MAIN_OBJECTS) $(BUILD_DIR)/lib/libnet.a $(BUILD_DIR)/lib/libcore.so | $(BUILD_DIR)/bin\n\
+             \t$(CC) $(CFLAGS) $(LDFLAGS) $(MAIN_OBJECTS) -L$(BUILD_DIR)/lib -lnet -lcore -Wl,-rpath,'$$ORIGIN/../lib' $(LDLIBS) -o $@\n"
+        ));
+    }
+
+    #[test]
+    fn static_libraries_are_archives_and_shared_ones_have_a_soname() {
+        let mut makefile = makefile(&[], &[("main.c", &["net/io.c", "core/log.c"])]);
+        makefile.set_libraries(vec![
+            library("net", LibraryKind::Static, None, &["net/io.c"]),
+            library("core", LibraryKind::Shared, Some("1.2.0"), &["core/log.c"]),
+        ]);
+        let contents = makefile.format();
+        assert!(contents.contains("$(BUILD_DIR)/lib/libnet.a: $(LIBNET_OBJECTS) | $(BUILD_DIR)/lib\n\t$(AR) rcs $@ $(LIBNET_OBJECTS)\n"));
+        assert!(contents.contains("-shared -Wl,-soname,libcore.so.1 $(LDFLAGS) $(LIBCORE_OBJECTS) $(LDLIBS) -o $(@D)/libcore.so.1.2.0\n"));
+        assert!(contents.contains("\tln -sf libcore.so.1.2.0 $(@D)/libcore.so.1\n\tln -sf libcore.so.1 $@\n"));
+        assert!(contents.contains("$(LIBCORE_OBJECTS): CFLAGS += -fPIC\n"));
+        assert!(!contents.contains("$(LIBNET_OBJECTS): CFLAGS"));
+    }
+
+    #[test]
+    fn a_file_linked_anyway_is_not_conditional() {
+        for order in [[true, false], [false, true]].iter() {
+            let makefile = makefile(&[], &[("main.c", &[])]);
+            order.iter().for_each(|conditional| makefile.add_dependency("main.c", "util.c".to_string(), *conditional));
+            let (_, sources, conditional) = makefile.link_sets().remove(0);
+            assert_eq!(sources, vec!["util.c".to_string()]);
+            assert!(conditional.is_empty());
+        }
+    }
+
+    #[test]
+    fn executables_find_the_shared_libraries_wherever_they_are() {
+        let mut makefile = makefile(&[], &[("main.c", &["net/io.c", "core/log.c"]), ("tools/gen/run.c", &["net/io.c"])]);
+        makefile.set_libraries(vec![
+            library("net", LibraryKind::Static, None, &["net/io.c"]),
+            library("core", LibraryKind::Shared, None, &["core/log.c"]),
+        ]);
+        let linkings = makefile.linkings();
+        let main = &linkings[0];
+        assert_eq!(main.sources, Vec::<String>::new());
+        assert_eq!(main.libraries.iter().map(|library| library.name.as_str()).collect::<Vec<&str>>(), vec!["net", "core"]);
+        assert_eq!(main.rpath.as_deref(), Some("-Wl,-rpath,'$$ORIGIN/../lib'"));
+        // Only static libraries, nothing to find
+        let run = &linkings[1];
+        assert_eq!(run.source, "tools/gen/run.c");
+        assert_eq!(run.libraries.len(), 1);
+        assert_eq!(run.rpath, None);
+    }
+}